@@ -0,0 +1,464 @@
+use std::{
+    collections::{BTreeSet, HashSet},
+    fmt, fs, io,
+    path::{Path, PathBuf},
+};
+
+/// Number of unchanged lines shown around changes.
+const CONTEXT: usize = 3;
+
+/// Largest number of line pairs compared to find a minimal diff. Bigger
+/// changes are shown as replacement of the whole changed block.
+const MAX_DIFF_CELLS: usize = 4_000_000;
+
+/// Difference between committed and freshly generated code.
+#[derive(Debug)]
+pub enum Drift {
+    /// Committed file differs from the generated one.
+    Stale {
+        path: PathBuf,
+        committed: Vec<u8>,
+        generated: Vec<u8>,
+    },
+    /// Generated file is not committed.
+    Missing { path: PathBuf, generated: Vec<u8> },
+    /// Committed file is not generated anymore.
+    Extra { path: PathBuf, committed: Vec<u8> },
+}
+
+/// Files of an output protogen considers its own, so they're reported as
+/// extra once they aren't generated anymore.
+///
+/// These are files recorded in the state file and files named like generated
+/// ones, e.g. `b_pb2.py` if `a_pb2.py` was generated for `a.proto`.
+#[derive(Debug)]
+pub struct Owned<'a> {
+    /// Recorded files, relative to the output.
+    pub recorded: BTreeSet<PathBuf>,
+    /// Names of compiled protos without extension.
+    pub stems: &'a HashSet<String>,
+}
+
+impl Owned<'_> {
+    /// Returns endings of `generated` file names following the name of their
+    /// proto, like `_pb2.py` or `.pb.go`. Bare extensions are left out, since
+    /// they'd match hand-written files too.
+    fn suffixes(&self, generated: &BTreeSet<PathBuf>) -> BTreeSet<String> {
+        let mut suffixes = BTreeSet::new();
+        for path in generated {
+            let name = match path.file_name().and_then(|n| n.to_str()) {
+                Some(name) => name,
+                None => continue,
+            };
+
+            for (idx, _) in name.match_indices(['.', '_', '-']) {
+                let suffix = &name[idx..];
+                if suffix[1..].contains('.') && self.stems.contains(&name[..idx]) {
+                    suffixes.insert(suffix.to_owned());
+                }
+            }
+        }
+
+        suffixes
+    }
+}
+
+/// Compares files committed to `committed_dir` with files generated into
+/// `generated_dir`.
+///
+/// Committed files which aren't generated are reported as extra only if
+/// they're `owned`, so hand-written files may live in outputs. Paths of
+/// drifts are prefixed with `prefix`.
+pub fn compare(
+    committed_dir: &Path,
+    generated_dir: &Path,
+    prefix: &Path,
+    owned: &Owned<'_>,
+) -> io::Result<Vec<Drift>> {
+    let committed = list_files(committed_dir)?;
+    let generated = list_files(generated_dir)?;
+    let suffixes = owned.suffixes(&generated);
+    let is_owned = |path: &Path| {
+        let name = path.file_name().and_then(|n| n.to_str()).unwrap_or("");
+        owned.recorded.contains(path)
+            || suffixes
+                .iter()
+                .any(|s| name.len() > s.len() && name.ends_with(s.as_str()))
+    };
+    let mut drifts = vec![];
+
+    for path in committed.union(&generated) {
+        let drift = match (committed.contains(path), generated.contains(path)) {
+            (true, true) => {
+                let committed = fs::read(committed_dir.join(path))?;
+                let generated = fs::read(generated_dir.join(path))?;
+                if committed == generated {
+                    continue;
+                }
+
+                Drift::Stale {
+                    path: prefix.join(path),
+                    committed,
+                    generated,
+                }
+            }
+            (false, true) => Drift::Missing {
+                path: prefix.join(path),
+                generated: fs::read(generated_dir.join(path))?,
+            },
+            (true, false) if !is_owned(path) => continue,
+            (true, false) => Drift::Extra {
+                path: prefix.join(path),
+                committed: fs::read(committed_dir.join(path))?,
+            },
+            (false, false) => unreachable!(),
+        };
+
+        drifts.push(drift);
+    }
+
+    Ok(drifts)
+}
+
+impl Drift {
+    pub fn path(&self) -> &Path {
+        match self {
+            Drift::Stale { path, .. } => path,
+            Drift::Missing { path, .. } => path,
+            Drift::Extra { path, .. } => path,
+        }
+    }
+}
+
+/// Returns relative paths of all files under `dir`, if it exists.
+pub fn list_files(dir: &Path) -> io::Result<BTreeSet<PathBuf>> {
+    let mut files = BTreeSet::new();
+    if !dir.is_dir() {
+        return Ok(files);
+    }
+
+    let mut dirs = vec![dir.to_owned()];
+    while let Some(current) = dirs.pop() {
+        for entry in fs::read_dir(&current)? {
+            let path = entry?.path();
+            if path.is_dir() {
+                dirs.push(path);
+            } else {
+                let relative = path.strip_prefix(dir).expect("unrelated path");
+                files.insert(relative.to_owned());
+            }
+        }
+    }
+
+    Ok(files)
+}
+
+impl fmt::Display for Drift {
+    /// Formats drift as a unified diff from committed to generated file.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let path = self.path().display();
+        let (old_name, old) = match self {
+            Drift::Stale { committed, .. } | Drift::Extra { committed, .. } => {
+                (format!("a/{}", path), &committed[..])
+            }
+            Drift::Missing { .. } => ("/dev/null".to_owned(), &[][..]),
+        };
+
+        let (new_name, new) = match self {
+            Drift::Stale { generated, .. } | Drift::Missing { generated, .. } => {
+                (format!("b/{}", path), &generated[..])
+            }
+            Drift::Extra { .. } => ("/dev/null".to_owned(), &[][..]),
+        };
+
+        let (old, new) = match (std::str::from_utf8(old), std::str::from_utf8(new)) {
+            (Ok(old), Ok(new)) => (old, new),
+            _ => return writeln!(f, "Binary files {} and {} differ", old_name, new_name),
+        };
+
+        writeln!(f, "--- {}", old_name)?;
+        writeln!(f, "+++ {}", new_name)?;
+
+        // line endings are kept, so that files differing only in them have a
+        // hunk too
+        let old: Vec<_> = old.split_inclusive('\n').collect();
+        let new: Vec<_> = new.split_inclusive('\n').collect();
+        let edits = diff_lines(&old, &new);
+        for hunk in hunks(&edits) {
+            write_hunk(f, &edits, hunk)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Edit<'a> {
+    Keep(&'a str),
+    Remove(&'a str),
+    Add(&'a str),
+}
+
+/// Returns line edits turning `old` into `new`.
+fn diff_lines<'a>(old: &[&'a str], new: &[&'a str]) -> Vec<Edit<'a>> {
+    let prefix = old.iter().zip(new).take_while(|(a, b)| a == b).count();
+    let suffix = old[prefix..]
+        .iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+
+    let old_mid = &old[prefix..old.len() - suffix];
+    let new_mid = &new[prefix..new.len() - suffix];
+
+    let mut edits: Vec<_> = old[..prefix].iter().map(|l| Edit::Keep(l)).collect();
+    if old_mid.len() * new_mid.len() > MAX_DIFF_CELLS {
+        edits.extend(old_mid.iter().map(|l| Edit::Remove(l)));
+        edits.extend(new_mid.iter().map(|l| Edit::Add(l)));
+    } else {
+        edits.extend(lcs_diff(old_mid, new_mid));
+    }
+
+    edits.extend(old[old.len() - suffix..].iter().map(|l| Edit::Keep(l)));
+    edits
+}
+
+/// Finds minimal diff via the longest common subsequence.
+fn lcs_diff<'a>(old: &[&'a str], new: &[&'a str]) -> Vec<Edit<'a>> {
+    let width = new.len() + 1;
+    let mut lengths = vec![0u32; (old.len() + 1) * width];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            lengths[i * width + j] = if old[i] == new[j] {
+                lengths[(i + 1) * width + j + 1] + 1
+            } else {
+                lengths[(i + 1) * width + j].max(lengths[i * width + j + 1])
+            };
+        }
+    }
+
+    let mut edits = Vec::with_capacity(old.len() + new.len());
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() && j < new.len() {
+        if old[i] == new[j] {
+            edits.push(Edit::Keep(old[i]));
+            i += 1;
+            j += 1;
+        } else if lengths[(i + 1) * width + j] >= lengths[i * width + j + 1] {
+            edits.push(Edit::Remove(old[i]));
+            i += 1;
+        } else {
+            edits.push(Edit::Add(new[j]));
+            j += 1;
+        }
+    }
+
+    edits.extend(old[i..].iter().map(|l| Edit::Remove(l)));
+    edits.extend(new[j..].iter().map(|l| Edit::Add(l)));
+    edits
+}
+
+/// Returns ranges of edits to show, each with changes and their context.
+fn hunks(edits: &[Edit<'_>]) -> Vec<(usize, usize)> {
+    let mut hunks: Vec<(usize, usize)> = vec![];
+    for (idx, edit) in edits.iter().enumerate() {
+        if let Edit::Keep(_) = edit {
+            continue;
+        }
+
+        let start = idx.saturating_sub(CONTEXT);
+        let end = (idx + CONTEXT + 1).min(edits.len());
+        match hunks.last_mut() {
+            Some(last) if start <= last.1 => last.1 = end,
+            _ => hunks.push((start, end)),
+        }
+    }
+
+    hunks
+}
+
+fn write_hunk(
+    f: &mut fmt::Formatter<'_>,
+    edits: &[Edit<'_>],
+    (start, end): (usize, usize),
+) -> fmt::Result {
+    let (old_start, new_start) = line_counts(&edits[..start]);
+    let (old_len, new_len) = line_counts(&edits[start..end]);
+    writeln!(
+        f,
+        "@@ -{} +{} @@",
+        hunk_range(old_start, old_len),
+        hunk_range(new_start, new_len)
+    )?;
+
+    for edit in &edits[start..end] {
+        let (marker, line) = match edit {
+            Edit::Keep(line) => (' ', line),
+            Edit::Remove(line) => ('-', line),
+            Edit::Add(line) => ('+', line),
+        };
+
+        write!(f, "{}{}", marker, line)?;
+        if !line.ends_with('\n') {
+            writeln!(f, "\n\\ No newline at end of file")?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Returns numbers of old and new lines covered by `edits`.
+fn line_counts(edits: &[Edit<'_>]) -> (usize, usize) {
+    edits.iter().fold((0, 0), |(old, new), edit| match edit {
+        Edit::Keep(_) => (old + 1, new + 1),
+        Edit::Remove(_) => (old + 1, new),
+        Edit::Add(_) => (old, new + 1),
+    })
+}
+
+/// Formats range of lines in a hunk header, `start` is zero-based.
+fn hunk_range(start: usize, len: usize) -> String {
+    match len {
+        0 => format!("{},0", start),
+        1 => format!("{}", start + 1),
+        _ => format!("{},{}", start + 1, len),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lines(text: &str) -> Vec<&str> {
+        text.split_inclusive('\n').collect()
+    }
+
+    fn stale(committed: &str, generated: &str) -> String {
+        let drift = Drift::Stale {
+            path: PathBuf::from("a.txt"),
+            committed: committed.as_bytes().to_vec(),
+            generated: generated.as_bytes().to_vec(),
+        };
+
+        drift.to_string()
+    }
+
+    #[test]
+    fn diffs_insertion_at_start_and_end() {
+        let old = lines("b\nc\n");
+        let new = lines("a\nb\nc\nd\n");
+        let edits = diff_lines(&old, &new);
+
+        assert_eq!(
+            edits,
+            vec![
+                Edit::Add("a\n"),
+                Edit::Keep("b\n"),
+                Edit::Keep("c\n"),
+                Edit::Add("d\n"),
+            ]
+        );
+        assert_eq!(hunks(&edits), vec![(0, 4)]);
+        assert_eq!(
+            stale("b\nc\n", "a\nb\nc\nd\n"),
+            "--- a/a.txt\n+++ b/a.txt\n@@ -1,2 +1,4 @@\n+a\n b\n c\n+d\n"
+        );
+    }
+
+    #[test]
+    fn diffs_pure_deletion() {
+        assert_eq!(hunk_range(3, 0), "3,0");
+        assert_eq!(hunk_range(3, 1), "4");
+        assert_eq!(hunk_range(3, 2), "4,2");
+
+        assert_eq!(
+            stale("a\nb\n", ""),
+            "--- a/a.txt\n+++ b/a.txt\n@@ -1,2 +0,0 @@\n-a\n-b\n"
+        );
+    }
+
+    #[test]
+    fn merges_close_hunks() {
+        let old: Vec<_> = (0..20).map(|i| format!("{}\n", i)).collect();
+        let old: Vec<_> = old.iter().map(String::as_str).collect();
+
+        // changes 6 lines apart share context, the last one is far away, and
+        // every replaced line is a removal followed by an addition
+        let mut new = old.clone();
+        new[2] = "x\n";
+        new[8] = "y\n";
+        new[18] = "z\n";
+        let edits = diff_lines(&old, &new);
+
+        assert_eq!(hunks(&edits), vec![(0, 14), (17, 23)]);
+    }
+
+    #[test]
+    fn diffs_trailing_newline() {
+        assert_eq!(
+            stale("a\nb\n", "a\nb"),
+            "--- a/a.txt\n+++ b/a.txt\n@@ -1,2 +1,2 @@\n a\n-b\n+b\n\\ No newline at end of file\n"
+        );
+    }
+
+    #[test]
+    fn diffs_line_endings() {
+        assert_eq!(
+            stale("a\r\nb\n", "a\nb\n"),
+            "--- a/a.txt\n+++ b/a.txt\n@@ -1,2 +1,2 @@\n-a\r\n+a\n b\n"
+        );
+    }
+
+    #[test]
+    fn reports_owned_extra_files_only() {
+        let dir = crate::temp::TempDir::new("protogen-compare").unwrap();
+        let committed = dir.path().join("committed");
+        let generated = dir.path().join("generated");
+        fs::create_dir(&committed).unwrap();
+        fs::create_dir(&generated).unwrap();
+        for name in ["a_pb2.py", "b_pb2.py", "c_pb2.py", "util.py", "go.mod"] {
+            fs::write(committed.join(name), name).unwrap();
+        }
+        fs::write(generated.join("a_pb2.py"), "a_pb2.py").unwrap();
+
+        let stems = ["a", "b", "c"].iter().map(|s| s.to_string()).collect();
+        let owned = Owned {
+            recorded: vec![PathBuf::from("go.mod")].into_iter().collect(),
+            stems: &stems,
+        };
+        let drifts = compare(&committed, &generated, Path::new("gen"), &owned).unwrap();
+        let paths: Vec<_> = drifts.iter().map(Drift::path).collect();
+
+        assert_eq!(
+            paths,
+            vec![
+                Path::new("gen/b_pb2.py"),
+                Path::new("gen/c_pb2.py"),
+                Path::new("gen/go.mod"),
+            ]
+        );
+        assert!(drifts.iter().all(|d| matches!(d, Drift::Extra { .. })));
+    }
+
+    #[test]
+    fn ignores_bare_extensions_as_suffixes() {
+        let stems = ["a"].iter().map(|s| s.to_string()).collect();
+        let owned = Owned {
+            recorded: BTreeSet::new(),
+            stems: &stems,
+        };
+        let generated = ["a.py", "a.pb.go", "a_grpc.pb.go", "sub/a_pb2.py"]
+            .iter()
+            .map(PathBuf::from)
+            .collect();
+
+        assert_eq!(
+            owned.suffixes(&generated),
+            ["_pb2.py", ".pb.go", "_grpc.pb.go"]
+                .iter()
+                .map(|s| s.to_string())
+                .collect()
+        );
+    }
+}
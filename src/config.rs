@@ -1,21 +1,87 @@
-use std::path::PathBuf;
+use std::{env, fmt, path::PathBuf, str::FromStr};
 
-use semver::Version;
-use serde::Deserialize;
+use semver::{ReqParseError, SemVerError, Version, VersionReq};
+use serde::{de, Deserialize, Deserializer};
 
 #[derive(Deserialize, Debug, Clone)]
 #[serde(deny_unknown_fields)]
 pub struct Config {
     pub protoc: Protoc,
+    #[serde(default)]
     pub plugins: Vec<Plugin>,
+    pub descriptor_set: Option<DescriptorSet>,
 }
 
 #[derive(Deserialize, Debug, Clone)]
 #[serde(deny_unknown_fields)]
 pub struct Protoc {
-    pub version: Version,
+    pub version: ProtocVersion,
+    pub source: Option<Source>,
+    /// Path to a protoc binary, implies `system` source.
+    pub path: Option<PathBuf>,
     pub include: Option<Vec<PathBuf>>,
     pub exclude: Option<Vec<PathBuf>>,
+    /// Local directory or zip with protoc releases to use instead of GitHub.
+    pub archives: Option<PathBuf>,
+    pub mirror: Option<Mirror>,
+    /// Number of times a failed download is retried.
+    pub retries: Option<u32>,
+    /// Platform to download protoc for instead of the current one.
+    pub platform: Option<String>,
+    /// Whether a version requirement may resolve to a pre-release.
+    pub prerelease: Option<bool>,
+    /// Number of protoc invocations to run in parallel.
+    pub jobs: Option<usize>,
+    /// Whether to pass arguments to protoc via a response file. By default
+    /// it's done for long command lines only.
+    pub args_file: Option<bool>,
+}
+
+/// Location of protoc releases if GitHub can't be reached directly.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Mirror {
+    /// Base URL of GitHub API, e.g. for GitHub Enterprise.
+    pub api_url: Option<String>,
+    /// Repository with protoc releases in `owner/name` format.
+    pub repository: Option<String>,
+    /// URL template of release archives to bypass GitHub API completely.
+    pub asset_url: Option<String>,
+}
+
+/// Binary `FileDescriptorSet` to write besides generated code.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct DescriptorSet {
+    /// Output file relative to the root. If it contains `{page}`, a set is
+    /// written per page instead of one for all protos.
+    pub output: PathBuf,
+    pub include_imports: Option<bool>,
+    pub include_source_info: Option<bool>,
+    /// Whether to keep options with source retention.
+    pub retain_options: Option<bool>,
+}
+
+/// Exact protoc version or a requirement it should satisfy.
+///
+/// Requirements are resolved against published releases and the resolved
+/// version is pinned in the lockfile.
+#[derive(Debug, Clone)]
+pub enum ProtocVersion {
+    Exact(Version),
+    Req(VersionReq),
+    /// The most recent release.
+    Latest,
+}
+
+/// Where protoc binary comes from.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Source {
+    /// Release archive downloaded into the cache.
+    Download,
+    /// Binary installed in the system.
+    System,
 }
 
 #[derive(Deserialize, Debug, Clone)]
@@ -25,4 +91,288 @@ pub struct Plugin {
     pub output: PathBuf,
     pub path: Option<PathBuf>,
     pub options: Option<String>,
+    /// Whether to remove files the plugin doesn't generate anymore.
+    pub clean: Option<bool>,
+    /// Commands run after the plugin generated code, e.g. formatters.
+    pub post: Option<Vec<String>>,
+}
+
+impl Protoc {
+    /// Creates protoc config with default settings.
+    pub fn new(version: ProtocVersion) -> Self {
+        Protoc {
+            version,
+            source: None,
+            path: None,
+            include: None,
+            exclude: None,
+            archives: None,
+            mirror: None,
+            retries: None,
+            platform: None,
+            prerelease: None,
+            jobs: None,
+            args_file: None,
+        }
+    }
+
+    pub fn source(&self) -> Source {
+        match (self.source, &self.path) {
+            (Some(source), _) => source,
+            (None, Some(_)) => Source::System,
+            (None, None) => Source::Download,
+        }
+    }
+}
+
+impl Plugin {
+    /// Placeholder of the output directory in `post` commands.
+    pub const OUTPUT: &'static str = "{output}";
+    /// Argument of `post` commands replaced with paths of generated files.
+    pub const FILES: &'static str = "{files}";
+    /// Environment variable with path of the plugin output passed to `post`
+    /// commands.
+    pub const OUTPUT_ENV: &'static str = "PROTOGEN_OUTPUT";
+
+    /// Returns whether there are commands to run after generation.
+    pub fn has_hooks(&self) -> bool {
+        self.post.as_ref().is_some_and(|p| !p.is_empty())
+    }
+}
+
+impl DescriptorSet {
+    /// Placeholder of the page name in `output`.
+    pub const PAGE: &'static str = "{page}";
+
+    pub fn new<P: Into<PathBuf>>(output: P) -> Self {
+        Self {
+            output: output.into(),
+            include_imports: None,
+            include_source_info: None,
+            retain_options: None,
+        }
+    }
+
+    pub fn per_page(&self) -> bool {
+        self.output.to_string_lossy().contains(Self::PAGE)
+    }
+}
+
+impl Mirror {
+    pub const API_URL_ENV: &'static str = "PROTOGEN_GITHUB_API_URL";
+    pub const REPOSITORY_ENV: &'static str = "PROTOGEN_GITHUB_REPOSITORY";
+    pub const ASSET_URL_ENV: &'static str = "PROTOGEN_ASSET_URL";
+
+    /// Returns a copy with values overridden by environment variables.
+    pub fn with_env(&self) -> Self {
+        let var = |name| env::var(name).ok().filter(|v: &String| !v.is_empty());
+
+        Self {
+            api_url: var(Self::API_URL_ENV).or_else(|| self.api_url.clone()),
+            repository: var(Self::REPOSITORY_ENV).or_else(|| self.repository.clone()),
+            asset_url: var(Self::ASSET_URL_ENV).or_else(|| self.asset_url.clone()),
+        }
+    }
+}
+
+impl ProtocVersion {
+    pub fn exact(&self) -> Option<&Version> {
+        match self {
+            ProtocVersion::Exact(v) => Some(v),
+            ProtocVersion::Req(_) | ProtocVersion::Latest => None,
+        }
+    }
+
+    pub fn matches(&self, version: &Version) -> bool {
+        match self {
+            ProtocVersion::Exact(v) => v == version,
+            ProtocVersion::Req(r) => r.matches(version),
+            ProtocVersion::Latest => true,
+        }
+    }
+
+    /// Returns whether `version` can be resolved from the requirement.
+    ///
+    /// Pre-releases are allowed only if `prerelease` is set or the version is
+    /// requested exactly.
+    pub fn allows(&self, version: &Version, prerelease: bool) -> bool {
+        if !version.is_prerelease() {
+            return self.matches(version);
+        }
+
+        match self {
+            ProtocVersion::Exact(v) => v == version,
+            _ if !prerelease => false,
+            ProtocVersion::Req(r) => {
+                let mut release = version.clone();
+                release.pre.clear();
+                r.matches(version) || r.matches(&release)
+            }
+            ProtocVersion::Latest => true,
+        }
+    }
+
+    /// Returns the greatest of `versions` allowed by the requirement.
+    pub fn resolve<'a, I>(&self, versions: I, prerelease: bool) -> Option<&'a Version>
+    where
+        I: IntoIterator<Item = &'a Version>,
+    {
+        versions
+            .into_iter()
+            .filter(|v| self.allows(v, prerelease))
+            .max()
+    }
+}
+
+/// Parses protoc version.
+///
+/// Releases since 21.0 don't have the `3.` prefix and are versioned like
+/// `21.12`, which is treated as `21.12.0`.
+pub fn parse_version(version: &str) -> Result<Version, SemVerError> {
+    let version = version.trim_start_matches('v');
+    let (release, pre) = match version.find('-') {
+        Some(idx) => version.split_at(idx),
+        None => (version, ""),
+    };
+
+    let major = release
+        .split('.')
+        .next()
+        .and_then(|m| m.parse::<u64>().ok());
+    if release.matches('.').count() == 1 && major.is_some_and(|m| m > 3) {
+        return Version::parse(&format!("{}.0{}", release, pre));
+    }
+
+    Version::parse(version)
+}
+
+impl FromStr for ProtocVersion {
+    type Err = ReqParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "latest" {
+            return Ok(ProtocVersion::Latest);
+        }
+
+        if let Ok(version) = parse_version(s) {
+            return Ok(ProtocVersion::Exact(version));
+        }
+
+        VersionReq::parse(s).map(ProtocVersion::Req)
+    }
+}
+
+impl<'de> Deserialize<'de> for ProtocVersion {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let version = String::deserialize(deserializer)?;
+        version.parse().map_err(de::Error::custom)
+    }
+}
+
+impl fmt::Display for ProtocVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProtocVersion::Exact(v) => v.fmt(f),
+            ProtocVersion::Req(r) => r.fmt(f),
+            ProtocVersion::Latest => write!(f, "latest"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn version(version: &str) -> Version {
+        Version::parse(version).unwrap()
+    }
+
+    #[test]
+    fn parses_protoc_versions() {
+        let cases = [
+            ("3.12.3", Some("3.12.3")),
+            ("v3.12.3", Some("3.12.3")),
+            ("21.12", Some("21.12.0")),
+            ("v21.12", Some("21.12.0")),
+            ("21.12.1", Some("21.12.1")),
+            ("22.0-rc1", Some("22.0.0-rc1")),
+            ("3.20.0-rc2", Some("3.20.0-rc2")),
+            ("3.12", None),
+            ("latest", None),
+        ];
+
+        for (input, expected) in &cases {
+            let parsed = parse_version(input).ok();
+            assert_eq!(parsed, expected.map(version), "{}", input);
+        }
+    }
+
+    #[test]
+    fn parses_requirements() {
+        let cases = [
+            ("3.12.3", "3.12.3"),
+            ("21.12", "21.12.0"),
+            ("^3.12", "^3.12"),
+            (">=21, <23", ">=21, <23"),
+            ("latest", "latest"),
+        ];
+
+        for (input, displayed) in &cases {
+            let required: ProtocVersion = input.parse().unwrap();
+            assert_eq!(required.to_string(), *displayed, "{}", input);
+        }
+
+        assert!(matches!("3.12".parse(), Ok(ProtocVersion::Req(_))));
+        assert!("three".parse::<ProtocVersion>().is_err());
+    }
+
+    #[test]
+    fn allows_prereleases_when_requested() {
+        let cases = [
+            // requirement, version, prerelease, allowed
+            ("^3.12", "3.20.0", false, true),
+            ("^3.12", "21.12.0", false, false),
+            ("^3.12", "3.20.0-rc2", false, false),
+            ("^3.12", "3.20.0-rc2", true, true),
+            ("3.20.0-rc2", "3.20.0-rc2", false, true),
+            ("3.20.0", "3.20.0-rc2", true, false),
+            ("latest", "22.0.0-rc1", false, false),
+            ("latest", "22.0.0-rc1", true, true),
+            ("latest", "21.12.0", false, true),
+        ];
+
+        for (required, v, prerelease, allowed) in &cases {
+            let required: ProtocVersion = required.parse().unwrap();
+            assert_eq!(
+                required.allows(&version(v), *prerelease),
+                *allowed,
+                "{} allows {} (prerelease = {})",
+                required,
+                v,
+                prerelease
+            );
+        }
+    }
+
+    #[test]
+    fn resolves_greatest_allowed_release() {
+        let releases: Vec<_> = ["3.12.3", "3.20.0-rc2", "3.20.0", "21.12.0", "22.0.0-rc1"]
+            .iter()
+            .map(|v| version(v))
+            .collect();
+
+        let cases = [
+            ("^3.12", false, Some("3.20.0")),
+            ("~3.12", false, Some("3.12.3")),
+            ("latest", false, Some("21.12.0")),
+            ("latest", true, Some("22.0.0-rc1")),
+            ("^4", false, None),
+        ];
+
+        for (required, prerelease, expected) in &cases {
+            let required: ProtocVersion = required.parse().unwrap();
+            let resolved = required.resolve(&releases, *prerelease);
+            assert_eq!(resolved, expected.map(version).as_ref(), "{}", required);
+        }
+    }
 }
@@ -0,0 +1,222 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    env,
+    ffi::OsString,
+    fs::{self, File},
+    io,
+    path::{Path, PathBuf},
+};
+
+use regex::Regex;
+use sha2::{Digest, Sha256};
+
+/// Computes fingerprints of protoc invocations.
+///
+/// Imports are scanned here rather than taken from `--dependency_out`, which
+/// older protoc versions refuse for more than one input file.
+pub struct Fingerprinter {
+    include_paths: Vec<PathBuf>,
+    import_re: Regex,
+    digests: HashMap<PathBuf, Option<String>>,
+}
+
+impl Fingerprinter {
+    /// Creates fingerprinter resolving imports against `include_paths` in order.
+    pub fn new(include_paths: Vec<PathBuf>) -> Self {
+        let import_re = Regex::new(r#"^\s*import\s+(?:public\s+|weak\s+)?"([^"]+)"\s*;"#)
+            .expect("invalid regex");
+
+        Self {
+            include_paths,
+            import_re,
+            digests: HashMap::new(),
+        }
+    }
+
+    /// Returns hash of the invocation arguments, content of `protos` and their
+    /// transitive imports and content of `binaries`.
+    ///
+    /// Returns `None` if some file can't be read, so the fingerprint never
+    /// matches.
+    pub fn fingerprint(
+        &mut self,
+        args: &[OsString],
+        protos: &[PathBuf],
+        binaries: &[PathBuf],
+    ) -> Option<String> {
+        let mut hasher = Sha256::new();
+        for arg in args {
+            hasher.update(arg.to_string_lossy().as_bytes());
+            hasher.update([0]);
+        }
+
+        for binary in binaries {
+            hasher.update(self.digest(binary)?.as_bytes());
+        }
+
+        for input in self.inputs(protos).ok()? {
+            hasher.update(input.as_bytes());
+            hasher.update([0]);
+        }
+
+        Some(format!("{:x}", hasher.finalize()))
+    }
+
+    /// Returns `protos` and their transitive imports along with digests of
+    /// their content.
+    ///
+    /// Imports which can't be resolved are listed too, so they are noticed
+    /// once they appear.
+    fn inputs(&mut self, protos: &[PathBuf]) -> io::Result<BTreeSet<String>> {
+        let mut inputs = BTreeSet::new();
+        let mut queue: Vec<_> = protos.to_vec();
+        let mut seen: BTreeSet<_> = queue.iter().cloned().collect();
+
+        while let Some(proto) = queue.pop() {
+            let digest = self
+                .digest(&proto)
+                .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "proto is unreadable"))?;
+            inputs.insert(format!("{}={}", proto.display(), digest));
+
+            for import in self.imports(&proto)? {
+                match self.resolve(&import) {
+                    Some(path) if seen.insert(path.clone()) => queue.push(path),
+                    Some(_) => (),
+                    None => {
+                        inputs.insert(format!("{}=?", import));
+                    }
+                }
+            }
+        }
+
+        Ok(inputs)
+    }
+
+    fn imports(&self, proto: &Path) -> io::Result<Vec<String>> {
+        let content = fs::read_to_string(proto)?;
+        let imports = content
+            .lines()
+            .filter_map(|line| self.import_re.captures(line))
+            .map(|c| c[1].to_owned())
+            .collect();
+
+        Ok(imports)
+    }
+
+    fn resolve(&self, import: &str) -> Option<PathBuf> {
+        self.include_paths
+            .iter()
+            .map(|p| p.join(import))
+            .find(|p| p.is_file())
+    }
+
+    /// Returns hash of the file content, computed once per file.
+    fn digest(&mut self, path: &Path) -> Option<String> {
+        if let Some(digest) = self.digests.get(path) {
+            return digest.clone();
+        }
+
+        let digest = file_digest(path).ok();
+        self.digests.insert(path.to_owned(), digest.clone());
+        digest
+    }
+}
+
+/// Looks up plugin binary in `PATH` the way protoc does.
+pub fn find_plugin(name: &str) -> Option<PathBuf> {
+    let name = format!("protoc-gen-{}{}", name, env::consts::EXE_SUFFIX);
+    let paths = env::var_os("PATH")?;
+    env::split_paths(&paths)
+        .map(|p| p.join(&name))
+        .find(|p| p.is_file())
+}
+
+fn file_digest(path: &Path) -> io::Result<String> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    io::copy(&mut file, &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::temp::TempDir;
+
+    fn write(root: &Path, proto: &str, content: &str) -> PathBuf {
+        let path = root.join(proto);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, content).unwrap();
+        path
+    }
+
+    /// Fingerprints `protos` with a new fingerprinter, so digests aren't
+    /// reused.
+    fn fingerprint(root: &Path, protos: &[PathBuf]) -> Option<String> {
+        let mut fingerprinter = Fingerprinter::new(vec![root.join("include"), root.to_owned()]);
+        fingerprinter.fingerprint(&[OsString::from("--python_out=gen")], protos, &[])
+    }
+
+    #[test]
+    fn changes_with_transitive_imports() {
+        let dir = TempDir::new("protogen-test-fingerprint-imports").unwrap();
+        let root = dir.path();
+        let a = write(root, "a.proto", "import \"b/b.proto\";\n");
+        write(root, "b/b.proto", "import public \"include/c.proto\";\n");
+        write(root, "include/c.proto", "// c\n");
+        write(root, "unrelated.proto", "// unrelated\n");
+        let protos = [a];
+
+        let first = fingerprint(root, &protos).unwrap();
+        assert_eq!(fingerprint(root, &protos), Some(first.clone()));
+
+        write(root, "unrelated.proto", "// changed\n");
+        assert_eq!(fingerprint(root, &protos), Some(first.clone()));
+
+        write(root, "include/c.proto", "// changed\n");
+        assert_ne!(fingerprint(root, &protos), Some(first));
+    }
+
+    #[test]
+    fn changes_once_unresolved_import_appears() {
+        let dir = TempDir::new("protogen-test-fingerprint-unresolved").unwrap();
+        let root = dir.path();
+        let a = write(root, "a.proto", "import \"missing.proto\";\n");
+        let protos = [a];
+
+        let first = fingerprint(root, &protos).unwrap();
+        assert_eq!(fingerprint(root, &protos), Some(first.clone()));
+
+        // the import is resolved against the include path first
+        write(root, "include/missing.proto", "// appeared\n");
+        let second = fingerprint(root, &protos).unwrap();
+        assert_ne!(second, first);
+
+        write(root, "missing.proto", "// shadowed\n");
+        assert_eq!(fingerprint(root, &protos), Some(second));
+    }
+
+    #[test]
+    fn changes_with_arguments_and_binaries() {
+        let dir = TempDir::new("protogen-test-fingerprint-args").unwrap();
+        let root = dir.path();
+        let protos = [write(root, "a.proto", "syntax = \"proto3\";\n")];
+        let binaries = [write(root, "protoc", "protoc 3.12.3")];
+        let mut fingerprinter = Fingerprinter::new(vec![root.to_owned()]);
+
+        let args = [OsString::from("--python_out=gen")];
+        let first = fingerprinter.fingerprint(&args, &protos, &binaries);
+        assert!(first.is_some());
+
+        let other_args = [OsString::from("--python_out=other")];
+        let second = fingerprinter.fingerprint(&other_args, &protos, &binaries);
+        assert_ne!(second, first);
+
+        let missing = [root.join("missing-protoc")];
+        assert_eq!(fingerprinter.fingerprint(&args, &protos, &missing), None);
+        assert_eq!(
+            fingerprinter.fingerprint(&args, &[root.join("missing.proto")], &[]),
+            None
+        );
+    }
+}
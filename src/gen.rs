@@ -1,28 +1,143 @@
-use std::{collections::HashSet, error, fmt, io, path::PathBuf, process::Command, rc::Rc};
+use std::{
+    collections::{hash_map::Entry, BTreeMap, HashMap, HashSet},
+    error,
+    ffi::OsString,
+    fmt, fs,
+    io::{self, Write},
+    iter::Peekable,
+    path::{Path, PathBuf},
+    process::{Command, ExitStatus, Output, Stdio},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::Instant,
+};
 
-use directories::BaseDirs;
+use log::{debug, info, warn};
+use semver::Version;
 
 use crate::{
-    config::Config,
+    check::{self, Drift},
+    config::{self, Config, DescriptorSet, Source},
+    fingerprint::{self, Fingerprinter},
+    lock::{AssetLock, LockError, Lockfile, LOCK_FILE},
     protoc::{
-        compiler::{go::GoError, AnyCompiler, Compiler, GoCompiler, PlainCompiler, Plugin},
-        provider::{DownloadError, GithubDownloader, ProtocProvider},
+        args_file::{self, ArgsFile},
+        compiler::{
+            go::GoError, AnyCompiler, Compiler, DescriptorSetCompiler, GoCompiler, PlainCompiler,
+            Plugin,
+        },
+        diagnostic::{self, Diagnostic},
+        provider::{
+            caches_path, AnyDownloader, AnyProvider, Archive, DownloadError, DownloadProgress,
+            GithubDownloader, LocalDownloader, ProtocDownloader, ProtocProvider, Retry,
+            SystemError, SystemProvider, UrlDownloader,
+        },
     },
-    walk::{deep::DeepProtoWalker, PagingProtoWalker, Walker},
+    state::{PageState, State, StateError, STATE_FILE},
+    temp::TempDir,
+    walk::{deep::DeepProtoWalker, PageKind, PagingProtoWalker, Walker},
+    watch::Watcher,
 };
 
+/// Name of invocations which write descriptor sets.
+pub const DESCRIPTOR_SET: &str = "descriptor_set";
+
+/// Name of the root page in descriptor set paths.
+const ROOT_PAGE_NAME: &str = "_root";
+
+/// Name of the plugin which needs Go import mappings.
+const GO_PLUGIN: &str = "go";
+
+/// Non-empty page of protos with its path relative to the root.
+type Page = (PathBuf, Peekable<DeepProtoWalker>);
+
 #[derive(Debug)]
 pub enum GenerateError {
-    NoProtoc(Box<dyn fmt::Debug + Send + Sync>),
+    NoProtoc(Box<dyn error::Error + Send + Sync>),
     ReadDirFailed(io::Error),
     InvocationFailed(Box<dyn fmt::Debug + Send + Sync>),
     ProtocFailed(io::Error),
+    CompilationFailed(Vec<PageFailure>),
+    LockFailed(LockError),
+    StateFailed(StateError),
+    CheckFailed(io::Error),
+    OutputFailed(io::Error),
+    InvalidHook(String, String),
+    HooksFailed(Vec<HookFailure>),
 }
 
+/// Describes a protoc invocation that exited with non-zero status or couldn't
+/// be started.
 #[derive(Debug)]
+pub struct PageFailure {
+    pub plugins: Vec<String>,
+    pub page: PathBuf,
+    /// Exit status of protoc or error starting it.
+    pub status: io::Result<ExitStatus>,
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+/// Describes a post-generation command of a plugin that failed.
+#[derive(Debug)]
+pub struct HookFailure {
+    pub plugin: String,
+    pub command: String,
+    /// Exit status of the command or error starting it.
+    pub result: io::Result<ExitStatus>,
+}
+
 pub struct Generator {
     root_path: PathBuf,
     config: Config,
+    locked: bool,
+    offline: bool,
+    platform: Option<String>,
+    progress: Option<Arc<dyn DownloadProgress>>,
+    jobs: Option<usize>,
+    fail_fast: bool,
+    force: bool,
+}
+
+/// Protoc invocation for a page of protos.
+#[derive(Debug, Clone)]
+pub struct Invocation {
+    /// Plugins run by the invocation.
+    pub plugins: Vec<String>,
+    pub page: PathBuf,
+    pub working_dir: PathBuf,
+    /// Protoc binary followed by its arguments.
+    pub args: Vec<OsString>,
+    /// Protos of the page.
+    pub protos: Vec<PathBuf>,
+    /// Binaries of plugins which aren't built into protoc.
+    pub plugin_paths: Vec<PathBuf>,
+    /// Temporary directories plugins write code to before it's moved to
+    /// their outputs.
+    pub staged: Vec<Stage>,
+}
+
+/// Temporary directory a plugin writes code to.
+#[derive(Debug, Clone)]
+pub struct Stage {
+    /// Index of the plugin in the config.
+    pub plugin: usize,
+    pub path: PathBuf,
+    /// Output the code is moved to afterwards.
+    pub output: PathBuf,
+}
+
+/// Where plugins write generated code to.
+enum OutputMode<'a> {
+    /// Plugin outputs.
+    Direct,
+    /// Directories plugins write to, by index of the plugin in the config.
+    Redirected(&'a [PathBuf]),
+    /// Directory under the path per invocation, so that generated files are
+    /// known.
+    Staged(&'a Path),
 }
 
 impl Generator {
@@ -31,80 +146,1116 @@ impl Generator {
         P: Into<PathBuf>,
     {
         let root_path = root_path.into();
-        Self { root_path, config }
+        Self {
+            root_path,
+            config,
+            locked: false,
+            offline: false,
+            platform: None,
+            progress: None,
+            jobs: None,
+            fail_fast: false,
+            force: false,
+        }
+    }
+
+    /// Sets whether the lockfile is allowed to be updated.
+    ///
+    /// If locked, protoc archives without recorded checksum will be refused.
+    pub fn set_locked(&mut self, locked: bool) {
+        self.locked = locked;
+    }
+
+    /// Sets whether protoc may be downloaded from the network.
+    ///
+    /// If offline, protoc is taken from the cache or from `protoc.archives`.
+    pub fn set_offline(&mut self, offline: bool) {
+        self.offline = offline;
+    }
+
+    /// Overrides the platform to download protoc for.
+    ///
+    /// Takes precedence over `protoc.platform`.
+    pub fn set_platform<S: Into<String>>(&mut self, platform: S) {
+        self.platform = Some(platform.into());
+    }
+
+    /// Sets receiver of protoc download progress.
+    pub fn set_progress(&mut self, progress: Arc<dyn DownloadProgress>) {
+        self.progress = Some(progress);
+    }
+
+    /// Sets number of protoc invocations to run in parallel.
+    ///
+    /// Takes precedence over `protoc.jobs`, defaults to the number of CPUs.
+    pub fn set_jobs(&mut self, jobs: usize) {
+        self.jobs = Some(jobs);
+    }
+
+    /// Sets whether to stop starting new invocations after a failed one.
+    pub fn set_fail_fast(&mut self, fail_fast: bool) {
+        self.fail_fast = fail_fast;
+    }
+
+    /// Sets whether to compile pages which are up to date.
+    pub fn set_force(&mut self, force: bool) {
+        self.force = force;
     }
 
     pub fn generate(&self) -> Result<(), GenerateError> {
+        self.validate_plugins()?;
+        let provider = self.make_provider(self.locked)?;
+        let protoc_path = provider
+            .binary_path()
+            .ok_or_else(|| GenerateError::NoProtoc("no protoc binary found".into()))?;
+        info!("using protoc at {:?}", protoc_path);
+
+        let include_path = provider.include_path();
+        let stage_dir = TempDir::new("protogen-stage").map_err(GenerateError::OutputFailed)?;
+        let mode = OutputMode::Staged(stage_dir.path());
+        let invocations = self.make_invocations(protoc_path, include_path, &mode)?;
+        self.create_output_dirs(&invocations)?;
+
+        let state_path = self.root_path.join(STATE_FILE);
+        let previous = State::load(&state_path);
+        let mut state = State::default();
+        let (invocations, mut fingerprints) = self.outdated_invocations(
+            invocations,
+            protoc_path,
+            include_path,
+            &previous,
+            &mut state,
+        );
+
+        let outputs = self.run(&invocations);
+        let (succeeded, failures) = self.report(&invocations, outputs);
+        let mut succeeded = succeeded.into_iter().peekable();
+        let mut pages = vec![];
+        let mut generated: BTreeMap<usize, Vec<PathBuf>> = BTreeMap::new();
+
+        for (idx, invocation) in invocations.iter().enumerate() {
+            let mut page = PageState::new(&invocation.page, &invocation.plugins);
+            if succeeded.next_if_eq(&idx).is_some() {
+                page.fingerprint = fingerprints[idx].take();
+                for stage in &invocation.staged {
+                    generated.entry(stage.plugin).or_default();
+                }
+
+                let files = self.unstage(invocation);
+                for (plugin, file) in files.map_err(GenerateError::OutputFailed)? {
+                    page.files.push(file.clone());
+                    generated.entry(plugin).or_default().push(file);
+                }
+            } else if let Some(previous) = previous.page(&invocation.page, &invocation.plugins) {
+                // files of failed pages are kept until they compile again
+                page.files = previous.files.clone();
+            }
+
+            pages.push(page);
+        }
+
+        let mut hook_failures = vec![];
+        for (plugin, files) in &generated {
+            let output = &self.config.plugins[*plugin].output;
+            if let Err(failure) = self.run_hooks(*plugin, output, files) {
+                // pages are compiled again by the next run, so are the hooks
+                for (invocation, page) in invocations.iter().zip(&mut pages) {
+                    if invocation.staged.iter().any(|s| s.plugin == *plugin) {
+                        page.fingerprint = None;
+                    }
+                }
+
+                hook_failures.push(failure);
+            }
+        }
+
+        for page in pages {
+            state.set_page(page);
+        }
+
+        self.prune(&previous, &mut state)
+            .map_err(GenerateError::OutputFailed)?;
+        state.save(&state_path)?;
+        if !failures.is_empty() {
+            for failure in hook_failures {
+                warn!("post command failed:\n{}", failure);
+            }
+
+            return Err(GenerateError::CompilationFailed(failures));
+        }
+
+        if !hook_failures.is_empty() {
+            return Err(GenerateError::HooksFailed(hook_failures));
+        }
+
+        Ok(())
+    }
+
+    /// Generates code into a temporary directory and compares it with code in
+    /// plugin outputs.
+    ///
+    /// Neither outputs, the state file nor the lockfile are touched. Descriptor
+    /// sets are not compared.
+    pub fn check(&self) -> Result<Vec<Drift>, GenerateError> {
+        self.validate_plugins()?;
+        // the lockfile is left as is, like with --locked
+        let provider = self.make_provider(true)?;
+        let protoc_path = provider
+            .binary_path()
+            .ok_or_else(|| GenerateError::NoProtoc("no protoc binary found".into()))?;
+        info!("using protoc at {:?}", protoc_path);
+
+        // plugins sharing an output share a temporary directory too, except
+        // ones with post commands which must see their own files only
+        let temp_dir = TempDir::new("protogen-check").map_err(GenerateError::CheckFailed)?;
+        let mut output_dirs: HashMap<PathBuf, PathBuf> = HashMap::new();
+        let mut redirects = vec![];
+        for (idx, plugin) in self.config.plugins.iter().enumerate() {
+            let dir = temp_dir
+                .path()
+                .join(format!("output-{}", output_dirs.len()));
+            let output = match output_dirs.entry(plugin.output.clone()) {
+                Entry::Occupied(entry) => entry.get().clone(),
+                Entry::Vacant(entry) => {
+                    fs::create_dir(&dir).map_err(GenerateError::CheckFailed)?;
+                    entry.insert(dir).clone()
+                }
+            };
+
+            let dir = match plugin.has_hooks() {
+                true => temp_dir.path().join(format!("plugin-{}", idx)),
+                false => output,
+            };
+
+            fs::create_dir_all(&dir).map_err(GenerateError::CheckFailed)?;
+            redirects.push(dir);
+        }
+
+        let invocations: Vec<_> = self
+            .make_invocations(
+                protoc_path,
+                provider.include_path(),
+                &OutputMode::Redirected(&redirects),
+            )?
+            .into_iter()
+            .filter(|i| i.plugins != [DESCRIPTOR_SET])
+            .collect();
+
+        let outputs = self.run(&invocations);
+        let (_, failures) = self.report(&invocations, outputs);
+        if !failures.is_empty() {
+            return Err(GenerateError::CompilationFailed(failures));
+        }
+
+        let mut hook_failures = vec![];
+        for (idx, plugin) in self.config.plugins.iter().enumerate() {
+            if !plugin.has_hooks() {
+                continue;
+            }
+
+            let dir = &redirects[idx];
+            let files = check::list_files(dir).map_err(GenerateError::CheckFailed)?;
+            let files: Vec<_> = files.into_iter().map(|f| dir.join(f)).collect();
+            if let Err(failure) = self.run_hooks(idx, dir, &files) {
+                hook_failures.push(failure);
+                continue;
+            }
+
+            copy_files(dir, &output_dirs[&plugin.output]).map_err(GenerateError::CheckFailed)?;
+        }
+
+        if !hook_failures.is_empty() {
+            return Err(GenerateError::HooksFailed(hook_failures));
+        }
+
+        let mut outputs: Vec<_> = output_dirs.into_iter().collect();
+        outputs.sort();
+
+        let state = State::load(&self.root_path.join(STATE_FILE));
+        let stems: HashSet<_> = invocations
+            .iter()
+            .flat_map(|i| &i.protos)
+            .filter_map(|p| p.file_stem()?.to_str().map(str::to_owned))
+            .collect();
+
+        let mut drifts = vec![];
+        for (output, dir) in outputs {
+            let committed = self.root_path.join(&output);
+            let owned = check::Owned {
+                recorded: state
+                    .files()
+                    .filter_map(|f| f.strip_prefix(&output).ok())
+                    .map(Path::to_owned)
+                    .collect(),
+                stems: &stems,
+            };
+            let found = check::compare(&committed, &dir, &output, &owned);
+            drifts.append(&mut found.map_err(GenerateError::CheckFailed)?);
+        }
+
+        Ok(drifts)
+    }
+
+    /// Replays outputs of protoc in order of invocations regardless of timing.
+    ///
+    /// Returns indices of succeeded invocations and failures of the others.
+    fn report(
+        &self,
+        invocations: &[Invocation],
+        outputs: Vec<Option<io::Result<Output>>>,
+    ) -> (Vec<usize>, Vec<PageFailure>) {
+        let mut succeeded = vec![];
+        let mut failures = vec![];
+
+        for (idx, (invocation, output)) in invocations.iter().zip(outputs).enumerate() {
+            let output = match output {
+                Some(Ok(output)) => output,
+                Some(Err(e)) => {
+                    failures.push(PageFailure {
+                        plugins: invocation.plugins.clone(),
+                        page: invocation.page.clone(),
+                        status: Err(e),
+                        diagnostics: vec![],
+                    });
+                    continue;
+                }
+                None => continue,
+            };
+
+            let _ = io::stdout().write_all(&output.stdout);
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            if output.status.success() {
+                // pass through warnings protoc may emit on success
+                eprint!("{}", stderr);
+                succeeded.push(idx);
+                continue;
+            }
+
+            failures.push(PageFailure {
+                plugins: invocation.plugins.clone(),
+                page: invocation.page.clone(),
+                status: Ok(output.status),
+                diagnostics: diagnostic::parse(&stderr),
+            });
+        }
+
+        (succeeded, failures)
+    }
+
+    /// Returns invocations whose inputs changed since the last run or whose
+    /// generated files are missing, along with their new fingerprints.
+    ///
+    /// Fingerprints of up to date invocations are carried over to `state`.
+    fn outdated_invocations(
+        &self,
+        invocations: Vec<Invocation>,
+        protoc_path: &Path,
+        include_path: Option<&Path>,
+        previous: &State,
+        state: &mut State,
+    ) -> (Vec<Invocation>, Vec<Option<String>>) {
+        let mut fingerprinter = Fingerprinter::new(self.include_paths(include_path));
+        let mut outdated = (vec![], vec![]);
+
+        for invocation in invocations {
+            // changed post commands need to run even if the code is the same
+            let mut args = invocation.unstaged_args();
+            for stage in &invocation.staged {
+                let plugin = &self.config.plugins[stage.plugin];
+                args.extend(plugin.post.iter().flatten().map(OsString::from));
+            }
+
+            // another protoc at the same path may generate different code
+            let mut binaries = vec![protoc_path.to_owned()];
+            binaries.extend(invocation.plugin_paths.iter().cloned());
+            let fingerprint = fingerprinter.fingerprint(&args, &invocation.protos, &binaries);
+
+            let recorded = previous
+                .page(&invocation.page, &invocation.plugins)
+                .filter(|p| fingerprint.is_some() && p.fingerprint == fingerprint);
+            match recorded {
+                Some(page) if !self.force && self.files_exist(page) => {
+                    let plugins = invocation.plugins.join(", ");
+                    info!("{:?} ({}) is up to date", invocation.page, plugins);
+                    state.set_page(page.clone());
+                }
+                _ => {
+                    outdated.0.push(invocation);
+                    outdated.1.push(fingerprint);
+                }
+            }
+        }
+
+        outdated
+    }
+
+    /// Returns protoc invocations without running them.
+    ///
+    /// Protoc is neither downloaded nor probed, so the binary may not exist
+    /// yet. Version requirement must be already pinned in the lockfile.
+    pub fn commands(&self) -> Result<Vec<Invocation>, GenerateError> {
+        self.validate_plugins()?;
+        let (protoc_path, include_path) = self.locate_protoc()?;
+        self.make_invocations(&protoc_path, include_path.as_deref(), &OutputMode::Direct)
+    }
+
+    /// Returns invocations of all plugins and descriptor sets for all pages.
+    fn make_invocations(
+        &self,
+        protoc_path: &Path,
+        include_path: Option<&Path>,
+        mode: &OutputMode<'_>,
+    ) -> Result<Vec<Invocation>, GenerateError> {
         let walker = self.make_walker(self.config.protoc.exclude.clone())?;
+        let mut invocations = vec![];
+
+        for group in self.plugin_groups() {
+            let configs: Vec<_> = group.iter().map(|&i| &self.config.plugins[i]).collect();
+            let names: Vec<_> = configs.iter().map(|p| p.name.clone()).collect();
+            let plugins: Vec<_> = group.iter().map(|&i| self.make_plugin(i, mode)).collect();
+            let plugin_paths: Vec<_> = plugins.iter().filter_map(|p| self.plugin_path(p)).collect();
+            let compiler = self.make_compiler(protoc_path, include_path, plugins.clone())?;
+
+            for page in self.pages(walker.clone()) {
+                let (page_path, page) = page?;
+                let mut staged_outputs = vec![];
 
-        for plugin_cfg in &self.config.plugins {
-            let plugin: Plugin = plugin_cfg.into();
-            let compiler = self.make_compiler(plugin)?;
+                // staging directories differ per page, hence the compiler too
+                let compiler = match mode {
+                    OutputMode::Staged(dir) => {
+                        let dir = dir.join(invocations.len().to_string());
+                        let mut plugins = plugins.clone();
+                        for (idx, plugin) in plugins.iter_mut().enumerate() {
+                            let path = dir.join(idx.to_string());
+                            plugin.redirect(&path);
+                            staged_outputs.push(Stage {
+                                plugin: group[idx],
+                                path,
+                                output: configs[idx].output.clone(),
+                            });
+                        }
 
-            for page in walker.clone() {
-                let mut page = page
-                    .map_err(|e| GenerateError::ReadDirFailed(e))?
-                    .peekable();
+                        self.make_compiler(protoc_path, include_path, plugins)?
+                    }
+                    _ => compiler.clone(),
+                };
+
+                let (args, protos) = self.args_for_page(compiler, page)?;
+                invocations.push(Invocation {
+                    plugins: names.clone(),
+                    page: page_path,
+                    working_dir: self.root_path.clone(),
+                    args,
+                    protos,
+                    plugin_paths: plugin_paths.clone(),
+                    staged: staged_outputs,
+                });
+            }
+        }
 
-                if page.peek().is_none() {
+        let descriptor_set = match self.config.descriptor_set {
+            Some(ref descriptor_set) => descriptor_set,
+            None => return Ok(invocations),
+        };
+
+        if descriptor_set.per_page() {
+            for page in self.pages(walker) {
+                let (page_path, page) = page?;
+                let compiler = self.make_descriptor_set_compiler(
+                    protoc_path,
+                    include_path,
+                    descriptor_set,
+                    &page_path,
+                )?;
+
+                let (args, protos) = self.args_for_page(compiler, page)?;
+                invocations.push(Invocation {
+                    plugins: vec![DESCRIPTOR_SET.to_owned()],
+                    page: page_path,
+                    working_dir: self.root_path.clone(),
+                    args,
+                    protos,
+                    plugin_paths: vec![],
+                    staged: vec![],
+                });
+            }
+        } else {
+            let page_path = PathBuf::from(".");
+            let mut protos = DeepProtoWalker::new(&self.root_path, self.make_excludes()).peekable();
+            if protos.peek().is_some() {
+                let compiler = self.make_descriptor_set_compiler(
+                    protoc_path,
+                    include_path,
+                    descriptor_set,
+                    &page_path,
+                )?;
+
+                let (args, protos) = self.args_for_page(compiler, protos)?;
+                invocations.push(Invocation {
+                    plugins: vec![DESCRIPTOR_SET.to_owned()],
+                    page: page_path,
+                    working_dir: self.root_path.clone(),
+                    args,
+                    protos,
+                    plugin_paths: vec![],
+                    staged: vec![],
+                });
+            }
+        }
+
+        Ok(invocations)
+    }
+
+    /// Checks plugins before protoc is looked up or downloaded.
+    fn validate_plugins(&self) -> Result<(), GenerateError> {
+        for plugin in &self.config.plugins {
+            for error in Plugin::from(plugin).validate() {
+                warn!("{}: {}", plugin.name, error);
+            }
+
+            for command in plugin.post.iter().flatten() {
+                if hook_args(command, &plugin.output, &[]).is_none() {
+                    let name = plugin.name.clone();
+                    return Err(GenerateError::InvalidHook(name, command.clone()));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Groups plugins so that each group runs in a single protoc invocation.
+    ///
+    /// Go plugins need their own import mappings and always run alone. Since
+    /// protoc merges options of generators with the same name, such plugins
+    /// are put into different groups.
+    ///
+    /// Returns indices of plugins in the config.
+    fn plugin_groups(&self) -> Vec<Vec<usize>> {
+        let plugins = &self.config.plugins;
+        let mut groups: Vec<Vec<usize>> = vec![];
+
+        for (idx, plugin) in plugins.iter().enumerate() {
+            if plugin.name == GO_PLUGIN {
+                groups.push(vec![idx]);
+                continue;
+            }
+
+            let group = groups.iter_mut().find(|g| {
+                plugins[g[0]].name != GO_PLUGIN && g.iter().all(|&i| plugins[i].name != plugin.name)
+            });
+
+            match group {
+                Some(group) => group.push(idx),
+                None => groups.push(vec![idx]),
+            }
+        }
+
+        groups
+    }
+
+    fn make_plugin(&self, idx: usize, mode: &OutputMode<'_>) -> Plugin {
+        let config = &self.config.plugins[idx];
+        let mut plugin: Plugin = config.into();
+        debug!(
+            "{}: output {:?}, options {:?}",
+            config.name, config.output, config.options
+        );
+
+        if let OutputMode::Redirected(redirects) = mode {
+            plugin.redirect(&redirects[idx]);
+        }
+
+        plugin
+    }
+
+    /// Returns whether files not generated anymore are removed from `output`.
+    ///
+    /// Plugins sharing an output share the setting too, so that one plugin
+    /// doesn't remove files of the other.
+    fn cleans(&self, output: &Path) -> bool {
+        self.config
+            .plugins
+            .iter()
+            .any(|p| p.output == output && p.clean == Some(true))
+    }
+
+    /// Returns output containing `file` if it's cleaned.
+    fn cleaned_output(&self, file: &Path) -> Option<&Path> {
+        self.config
+            .plugins
+            .iter()
+            .map(|p| p.output.as_path())
+            .find(|o| file.starts_with(o) && self.cleans(o))
+    }
+
+    /// Returns whether files generated for the page by the previous run are
+    /// still there.
+    fn files_exist(&self, page: &PageState) -> bool {
+        match page.files.iter().find(|f| !self.root_path.join(f).exists()) {
+            Some(file) => {
+                info!("{:?} is missing, compiling {:?} again", file, page.page);
+                false
+            }
+            None => true,
+        }
+    }
+
+    /// Copies code generated into staging directories to plugin outputs.
+    ///
+    /// Files with unchanged content are left untouched. Returns paths of all
+    /// generated files along with indices of plugins which generated them.
+    fn unstage(&self, invocation: &Invocation) -> io::Result<Vec<(usize, PathBuf)>> {
+        let mut files = vec![];
+        for stage in &invocation.staged {
+            for file in check::list_files(&stage.path)? {
+                let content = fs::read(stage.path.join(&file))?;
+                let path = stage.output.join(&file);
+                let target = self.root_path.join(&path);
+
+                if fs::read(&target).ok().as_ref() != Some(&content) {
+                    if let Some(parent) = target.parent() {
+                        fs::create_dir_all(parent)?;
+                    }
+
+                    fs::write(&target, content)?;
+                }
+
+                files.push((stage.plugin, path));
+            }
+        }
+
+        Ok(files)
+    }
+
+    /// Runs post commands of the plugin at `idx` in the config, stopping at
+    /// the first failed one.
+    ///
+    /// Commands run in the root directory with `output` and `files` in place
+    /// of placeholders. The plugin output is passed in `PROTOGEN_OUTPUT` even
+    /// if code is generated elsewhere, so commands can find config files.
+    fn run_hooks(&self, idx: usize, output: &Path, files: &[PathBuf]) -> Result<(), HookFailure> {
+        let plugin = &self.config.plugins[idx];
+        for command in plugin.post.iter().flatten() {
+            let args = hook_args(command, output, files).expect("post command is validated");
+            info!("running post command of {}: {}", plugin.name, command);
+            debug!("post command: {:?}", args);
+
+            let result = Command::new(&args[0])
+                .args(&args[1..])
+                .current_dir(&self.root_path)
+                .env(
+                    config::Plugin::OUTPUT_ENV,
+                    self.root_path.join(&plugin.output),
+                )
+                .stdin(Stdio::null())
+                .output();
+
+            let result = match result {
+                Ok(output) => {
+                    let _ = io::stdout().write_all(&output.stdout);
+                    let _ = io::stderr().write_all(&output.stderr);
+                    match output.status.success() {
+                        true => continue,
+                        false => Ok(output.status),
+                    }
+                }
+                Err(e) => Err(e),
+            };
+
+            return Err(HookFailure {
+                plugin: plugin.name.clone(),
+                command: command.clone(),
+                result,
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Removes files generated by the previous run which are not generated
+    /// anymore, along with directories left empty.
+    ///
+    /// Files in outputs which aren't cleaned are kept in `state` while they
+    /// exist, so that they're removed once the output is cleaned.
+    fn prune(&self, previous: &State, state: &mut State) -> io::Result<()> {
+        let generated: HashSet<_> = state.pages.iter().flat_map(|p| &p.files).collect();
+        let mut kept = vec![];
+        let files = previous
+            .pages
+            .iter()
+            .flat_map(|p| &p.files)
+            .chain(&previous.kept);
+        for file in files {
+            if generated.contains(file) {
+                continue;
+            }
+
+            let output = match self.cleaned_output(file) {
+                Some(output) => self.root_path.join(output),
+                None if self.root_path.join(file).exists() => {
+                    kept.push(file.clone());
                     continue;
                 }
+                None => continue,
+            };
+
+            let path = self.root_path.join(file);
+            match fs::remove_file(&path) {
+                Ok(()) => info!("removed {:?} which is not generated anymore", file),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e),
+            }
 
-                let mut command = self.command_for_page(compiler.clone(), page)?;
-                command.current_dir(&self.root_path);
+            let mut dir = path.parent();
+            while let Some(parent) = dir {
+                if parent == output || fs::remove_dir(parent).is_err() {
+                    break;
+                }
 
-                let mut child = command
-                    .spawn()
-                    .map_err(|e| GenerateError::ProtocFailed(e))?;
-                child.wait().map_err(|e| GenerateError::ProtocFailed(e))?;
+                dir = parent.parent();
             }
         }
 
+        state.kept = kept;
         Ok(())
     }
 
-    fn command_for_page<C, W>(&self, mut compiler: C, page: W) -> Result<Command, GenerateError>
+    /// Returns non-empty pages along with their paths relative to the root.
+    fn pages<I>(&self, pages: I) -> impl Iterator<Item = Result<Page, GenerateError>> + '_
+    where
+        I: Iterator<Item = io::Result<DeepProtoWalker>> + 'static,
+    {
+        pages.filter_map(move |page| {
+            let page = match page {
+                Ok(page) => page,
+                Err(e) => return Some(Err(GenerateError::ReadDirFailed(e))),
+            };
+
+            let page_path = page.root().to_owned();
+            let page_path = match page_path.strip_prefix(&self.root_path) {
+                Ok(p) if p.as_os_str().is_empty() => PathBuf::from("."),
+                Ok(p) => p.to_owned(),
+                Err(_) => page_path,
+            };
+
+            let mut page = page.peekable();
+            page.peek()?;
+            Some(Ok((page_path, page)))
+        })
+    }
+
+    /// Creates staging directories and directories for descriptor sets since
+    /// protoc doesn't.
+    fn create_output_dirs(&self, invocations: &[Invocation]) -> Result<(), GenerateError> {
+        for stage in invocations.iter().flat_map(|i| &i.staged) {
+            fs::create_dir_all(&stage.path).map_err(GenerateError::OutputFailed)?;
+        }
+
+        let descriptor_set = match self.config.descriptor_set {
+            Some(ref descriptor_set) => descriptor_set,
+            None => return Ok(()),
+        };
+
+        for invocation in invocations.iter().filter(|i| i.plugins == [DESCRIPTOR_SET]) {
+            let path = self.descriptor_set_path(descriptor_set, &invocation.page);
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent).map_err(GenerateError::OutputFailed)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns path of the descriptor set for the page at `page_path`.
+    fn descriptor_set_path(&self, descriptor_set: &DescriptorSet, page_path: &Path) -> PathBuf {
+        let output = descriptor_set.output.to_string_lossy();
+        let page_name = match page_path.to_string_lossy() {
+            name if name == "." => ROOT_PAGE_NAME.into(),
+            name => name,
+        };
+
+        self.root_path
+            .join(output.replace(DescriptorSet::PAGE, &page_name))
+    }
+
+    /// Runs invocations in parallel and returns their outputs in the same
+    /// order.
+    ///
+    /// In fail-fast mode invocations which haven't started before a failure
+    /// are skipped and have no output.
+    fn run(&self, invocations: &[Invocation]) -> Vec<Option<io::Result<Output>>> {
+        let next = AtomicUsize::new(0);
+        let failed = AtomicBool::new(false);
+        let outputs: Vec<_> = invocations.iter().map(|_| Mutex::new(None)).collect();
+
+        let jobs = self.jobs().min(invocations.len()).max(1);
+        thread::scope(|scope| {
+            for _ in 0..jobs {
+                scope.spawn(|| loop {
+                    if self.fail_fast && failed.load(Ordering::SeqCst) {
+                        break;
+                    }
+
+                    let idx = next.fetch_add(1, Ordering::SeqCst);
+                    let invocation = match invocations.get(idx) {
+                        Some(invocation) => invocation,
+                        None => break,
+                    };
+
+                    let plugins = invocation.plugins.join(", ");
+                    info!("compiling {:?} ({})", invocation.page, plugins);
+                    debug!("running {:?}", invocation.args);
+
+                    let started = Instant::now();
+                    let output = self.output(&invocation.args);
+                    if !matches!(output, Ok(ref o) if o.status.success()) {
+                        failed.store(true, Ordering::SeqCst);
+                    }
+
+                    debug!(
+                        "compiled {:?} ({}) in {:.2?}",
+                        invocation.page,
+                        plugins,
+                        started.elapsed()
+                    );
+
+                    *outputs[idx].lock().unwrap() = Some(output);
+                });
+            }
+        });
+
+        outputs
+            .into_iter()
+            .map(|o| o.into_inner().unwrap())
+            .collect()
+    }
+
+    fn jobs(&self) -> usize {
+        match self.jobs.or(self.config.protoc.jobs) {
+            Some(jobs) if jobs > 0 => jobs,
+            _ => thread::available_parallelism().map_or(1, |n| n.get()),
+        }
+    }
+
+    /// Returns protoc arguments for the page along with its protos.
+    fn args_for_page<C, W>(
+        &self,
+        mut compiler: C,
+        page: W,
+    ) -> Result<(Vec<OsString>, Vec<PathBuf>), GenerateError>
     where
         C: Compiler,
         W: Walker,
     {
+        let protos: Vec<_> = page
+            .collect::<io::Result<_>>()
+            .map_err(GenerateError::ReadDirFailed)?;
         compiler
-            .set_protos(page)
-            .map_err(|e| GenerateError::ReadDirFailed(e))?;
-        let mut raw_command = compiler.command();
+            .set_protos(protos.clone().into_iter().map(Ok))
+            .map_err(GenerateError::ReadDirFailed)?;
+        let raw_command = compiler.command();
         if raw_command.is_empty() {
             return Err(GenerateError::InvocationFailed(Box::new(
                 "empty invocation",
             )));
         }
 
-        let mut cmd = Command::new(raw_command.remove(0));
-        cmd.args(raw_command);
+        Ok((raw_command, protos))
+    }
 
-        Ok(cmd)
+    /// Runs protoc, passing arguments via a response file if needed.
+    fn output(&self, args: &[OsString]) -> io::Result<Output> {
+        let use_args_file = match self.config.protoc.args_file {
+            Some(use_args_file) => use_args_file,
+            None => args_file::command_len(args) > args_file::THRESHOLD,
+        };
+
+        if !use_args_file {
+            return self.command(args).output();
+        }
+
+        let args_file = ArgsFile::create(&args[1..])?;
+        debug!("passing arguments via {:?}", args_file.path());
+        self.command(&[args[0].clone(), args_file.arg()]).output()
+    }
+
+    fn command(&self, args: &[OsString]) -> Command {
+        let mut cmd = Command::new(&args[0]);
+        cmd.args(&args[1..]);
+        cmd.current_dir(&self.root_path);
+        cmd.stdout(Stdio::piped());
+        cmd.stderr(Stdio::piped());
+        cmd
     }
 
-    fn make_compiler(&self, plugin: Plugin) -> Result<impl Compiler, GenerateError> {
-        let dirs = BaseDirs::new()
-            .ok_or_else(|| GenerateError::NoProtoc(Box::new("can't create protoc cache")))?;
-        let caches_path = dirs.cache_dir();
+    /// Returns paths of protoc binary and well-known protos without running or
+    /// downloading protoc.
+    fn locate_protoc(&self) -> Result<(PathBuf, Option<PathBuf>), GenerateError> {
+        match self.config.protoc.source() {
+            Source::Download => {
+                let lock = Lockfile::load(&self.root_path.join(LOCK_FILE))?;
+                let version = match self.config.protoc.version.exact() {
+                    Some(version) => version,
+                    None => self.pinned_version(&lock).ok_or_else(|| {
+                        let message = format!(
+                            "protoc {} isn't pinned in {}",
+                            self.config.protoc.version, LOCK_FILE
+                        );
+                        GenerateError::NoProtoc(message.into())
+                    })?,
+                };
 
-        let downloader = GithubDownloader::default();
-        let provider = ProtocProvider::new(&self.config.protoc.version, downloader, caches_path);
+                let provider = self.make_cached_provider(version)?;
+                let binary_path = provider.cached_binary_path().to_owned();
+                let include_path = provider.cached_include_path().to_owned();
+                Ok((binary_path, Some(include_path)))
+            }
+            Source::System => {
+                let binary_path = match self.config.protoc.path {
+                    Some(ref path) => self.root_path.join(path),
+                    None => SystemProvider::which()?,
+                };
 
-        if provider.binary_path().is_none() {
-            provider.download()?;
+                let include_path = SystemProvider::find_include_path(&binary_path);
+                Ok((binary_path, include_path))
+            }
         }
+    }
 
-        let protoc_path = provider
-            .binary_path()
-            .ok_or_else(|| GenerateError::NoProtoc(Box::new("no protoc binary found")))?;
+    /// Makes provider of protoc. If `locked`, the lockfile isn't updated and
+    /// versions or archives missing from it are refused.
+    fn make_provider(&self, locked: bool) -> Result<AnyProvider, GenerateError> {
+        match self.config.protoc.source() {
+            Source::Download => self
+                .make_download_provider(locked)
+                .map(AnyProvider::Download),
+            Source::System => self.make_system_provider().map(AnyProvider::System),
+        }
+    }
+
+    fn make_system_provider(&self) -> Result<SystemProvider, GenerateError> {
+        let provider = match self.config.protoc.path {
+            Some(ref path) => SystemProvider::new(self.root_path.join(path))?,
+            None => SystemProvider::find()?,
+        };
+
+        info!("found system protoc {}", provider.version());
+        let version = &self.config.protoc.version;
+        if !version.matches(provider.version()) {
+            return Err(SystemError::VersionMismatch {
+                found: provider.version().clone(),
+                required: version.to_string(),
+            }
+            .into());
+        }
+
+        Ok(provider)
+    }
+
+    fn make_download_provider(
+        &self,
+        locked: bool,
+    ) -> Result<ProtocProvider<AnyDownloader>, GenerateError> {
+        let lock_path = self.root_path.join(LOCK_FILE);
+        let mut lock = Lockfile::load(&lock_path)?;
+        let version = match self.config.protoc.version.exact() {
+            Some(version) => version.clone(),
+            None => self.resolve_version(&lock, locked)?,
+        };
+
+        let version = &version;
+        let mut provider = self.make_cached_provider(version)?;
+        let locked_asset = lock.protoc_asset(version, provider.platform()).cloned();
+
+        match locked_asset {
+            Some(ref asset) => provider.set_checksum(asset.sha256.as_str()),
+            None if locked => {
+                return Err(GenerateError::LockFailed(LockError::Outdated(format!(
+                    "no checksum of protoc {} for {}",
+                    version,
+                    provider.platform()
+                ))));
+            }
+            None => (),
+        }
+
+        let record = locked_asset.is_none();
+        if provider.is_installed() {
+            debug!("protoc {} for {} is cached", version, provider.platform());
+        } else {
+            info!(
+                "protoc {} for {} isn't cached",
+                version,
+                provider.platform()
+            );
+        }
+
+        if !provider.is_installed() || record {
+            let archive = provider.download()?;
+
+            if record {
+                let asset = AssetLock {
+                    platform: provider.platform().to_owned(),
+                    name: archive.name,
+                    sha256: archive.sha256,
+                };
+
+                lock.set_protoc_asset(version, asset);
+                lock.save(&lock_path)?;
+                info!("recorded checksum of protoc {} in {}", version, LOCK_FILE);
+            }
+        }
+
+        // usage is informational, failing to record it isn't fatal
+        let _ = provider.mark_used();
+        Ok(provider)
+    }
+
+    /// Resolves version requirement to the pinned version if it still matches
+    /// or to the greatest matching release otherwise.
+    fn resolve_version(&self, lock: &Lockfile, locked: bool) -> Result<Version, GenerateError> {
+        let required = &self.config.protoc.version;
+        let prerelease = self.config.protoc.prerelease.unwrap_or(false);
+
+        if let Some(pinned) = self.pinned_version(lock) {
+            debug!("protoc {} is pinned to {}", required, pinned);
+            return Ok(pinned.clone());
+        }
+
+        if locked {
+            return Err(GenerateError::LockFailed(LockError::Outdated(format!(
+                "no protoc version matching {} is pinned",
+                required
+            ))));
+        }
+
+        let releases = self.make_downloader().releases()?;
+        match required.resolve(&releases, prerelease) {
+            Some(version) => {
+                info!("resolved protoc {} to {}", required, version);
+                Ok(version.clone())
+            }
+            None => Err(GenerateError::NoProtoc(
+                format!("no protoc release matches {}", required).into(),
+            )),
+        }
+    }
+
+    /// Returns version pinned in the lockfile if it satisfies the requirement.
+    fn pinned_version<'a>(&self, lock: &'a Lockfile) -> Option<&'a Version> {
+        let prerelease = self.config.protoc.prerelease.unwrap_or(false);
+        lock.protoc_version()
+            .filter(|pinned| self.config.protoc.version.allows(pinned, prerelease))
+    }
+
+    /// Downloads protoc `version` into the cache unless it's already there.
+    pub fn fetch_protoc(&self, version: &Version) -> Result<Archive, GenerateError> {
+        let provider = self.make_cached_provider(version)?;
+        if provider.is_installed() {
+            if let Some(archive) = provider.installed_archive() {
+                return Ok(archive);
+            }
+        }
+
+        Ok(provider.download()?)
+    }
+
+    fn make_cached_provider(
+        &self,
+        version: &Version,
+    ) -> Result<ProtocProvider<AnyDownloader>, GenerateError> {
+        let caches_path = caches_path()
+            .ok_or_else(|| GenerateError::NoProtoc("can't create protoc cache".into()))?;
+
+        let downloader = self.make_downloader();
+        let mut provider = ProtocProvider::new(version, downloader, caches_path);
+        let platform = self.platform.as_ref();
+        if let Some(platform) = platform.or(self.config.protoc.platform.as_ref()) {
+            provider.set_platform(platform.as_str());
+        }
+
+        Ok(provider)
+    }
+
+    fn make_downloader(&self) -> AnyDownloader {
+        let mut downloader = match self.config.protoc.archives {
+            Some(ref path) => AnyDownloader::Local(LocalDownloader::new(self.root_path.join(path))),
+            None if self.offline => AnyDownloader::Local(LocalDownloader::default()),
+            None => self.make_remote_downloader(),
+        };
+
+        if let Some(ref progress) = self.progress {
+            downloader.set_progress(progress.clone());
+        }
+
+        downloader
+    }
+
+    fn make_remote_downloader(&self) -> AnyDownloader {
+        let mirror = self.config.protoc.mirror.clone().unwrap_or_default();
+        let mirror = mirror.with_env();
+
+        let mut retry = Retry::default();
+        if let Some(retries) = self.config.protoc.retries {
+            retry.retries = retries;
+        }
+
+        if let Some(template) = mirror.asset_url {
+            let mut downloader = UrlDownloader::with_template(template);
+            downloader.set_retry(retry);
+            return AnyDownloader::Url(downloader);
+        }
+
+        let mut downloader = GithubDownloader::default();
+        downloader.set_retry(retry);
+        if let Some(url) = mirror.api_url {
+            downloader.set_api_url(url);
+        }
+
+        if let Some(repository) = mirror.repository {
+            downloader.set_repository(repository);
+        }
+
+        if let Some(token) = downloader.token_from_env() {
+            downloader.set_token(token);
+        }
+
+        AnyDownloader::Github(downloader)
+    }
+
+    fn make_descriptor_set_compiler(
+        &self,
+        protoc_path: &Path,
+        include_path: Option<&Path>,
+        descriptor_set: &DescriptorSet,
+        page_path: &Path,
+    ) -> Result<AnyCompiler, GenerateError> {
+        let output = self.descriptor_set_path(descriptor_set, page_path);
+        let mut compiler = DescriptorSetCompiler::new(protoc_path, output);
+        compiler.set_include_imports(descriptor_set.include_imports.unwrap_or(false));
+        compiler.set_include_source_info(descriptor_set.include_source_info.unwrap_or(false));
+        compiler.set_retain_options(descriptor_set.retain_options.unwrap_or(false));
+
+        let mut compiler = AnyCompiler::DescriptorSet(compiler);
+        self.add_includes(&mut compiler, include_path)?;
+        Ok(compiler)
+    }
+
+    fn make_compiler(
+        &self,
+        protoc_path: &Path,
+        include_path: Option<&Path>,
+        plugins: Vec<Plugin>,
+    ) -> Result<impl Compiler, GenerateError> {
+        let mut plugins = plugins.into_iter();
+        let plugin = plugins.next().expect("empty plugin group");
 
         let mut compiler = match plugin.name() {
-            "go" => {
+            GO_PLUGIN => {
                 let mut compiler = GoCompiler::new(protoc_path, plugin)?;
 
-                if let Some(path) = provider.include_path() {
+                if let Some(path) = include_path {
                     compiler.set_compiler_includes(path);
                 }
 
@@ -114,48 +1265,113 @@ impl Generator {
 
                 AnyCompiler::Go(compiler)
             }
-            _ => AnyCompiler::Plain(PlainCompiler::new(protoc_path, plugin)),
+            _ => {
+                let mut compiler = PlainCompiler::new(protoc_path, plugin);
+                plugins.for_each(|p| compiler.add_plugin(p));
+                AnyCompiler::Plain(compiler)
+            }
         };
 
-        if let Some(path) = provider.include_path() {
+        self.add_includes(&mut compiler, include_path)?;
+        Ok(compiler)
+    }
+
+    /// Returns absolute include paths in the order they are passed to protoc.
+    fn include_paths(&self, include_path: Option<&Path>) -> Vec<PathBuf> {
+        let mut paths: Vec<_> = include_path.into_iter().map(Path::to_owned).collect();
+        paths.push(self.root_path.clone());
+        if let Some(ref includes) = self.config.protoc.include {
+            paths.extend(includes.iter().map(|p| self.root_path.join(p)));
+        }
+
+        paths
+    }
+
+    /// Returns path of the plugin binary if it's not built into protoc and
+    /// can be found.
+    fn plugin_path(&self, plugin: &Plugin) -> Option<PathBuf> {
+        if plugin.builtin().is_some() {
+            return None;
+        }
+
+        match plugin.path() {
+            Some(path) => Some(self.root_path.join(path)),
+            None => fingerprint::find_plugin(plugin.name()),
+        }
+    }
+
+    /// Adds well-known protos, the root and configured includes.
+    fn add_includes<C: Compiler>(
+        &self,
+        compiler: &mut C,
+        include_path: Option<&Path>,
+    ) -> Result<(), GenerateError> {
+        if let Some(path) = include_path {
             compiler
                 .add_include(path)
-                .map_err(|e| GenerateError::ProtocFailed(e))?;
+                .map_err(GenerateError::ProtocFailed)?;
         }
 
         compiler
             .add_include(&self.root_path)
-            .map_err(|e| GenerateError::ProtocFailed(e))?;
+            .map_err(GenerateError::ProtocFailed)?;
 
         if let Some(ref includes) = self.config.protoc.include {
             for include in includes.iter() {
                 compiler
                     .add_include(include)
-                    .map_err(|e| GenerateError::ProtocFailed(e))?;
+                    .map_err(GenerateError::ProtocFailed)?;
             }
         }
 
-        Ok(compiler)
+        Ok(())
+    }
+
+    /// Returns watcher of protos under the root honoring `protoc.exclude`.
+    pub fn watcher(&self) -> Watcher {
+        let mut watcher = Watcher::new(self.root_path.clone());
+        watcher.set_exclude(self.config.protoc.exclude.iter().flatten());
+        watcher
+    }
+
+    /// Returns page the file at `path` belongs to relative to the root, or
+    /// `None` if it's excluded or outside of the root.
+    pub fn page_of(&self, path: &Path) -> Option<PathBuf> {
+        let walker = self.make_walker(self.config.protoc.exclude.clone()).ok()?;
+        let (page, _) = walker.page_of(path)?;
+        match page.strip_prefix(&self.root_path) {
+            Ok(p) if p.as_os_str().is_empty() => Some(PathBuf::from(".")),
+            Ok(p) => Some(p.to_owned()),
+            Err(_) => Some(page),
+        }
+    }
+
+    fn make_excludes(&self) -> Arc<HashSet<PathBuf>> {
+        let excludes = self.config.protoc.exclude.iter().flatten();
+        Arc::new(excludes.map(|p| self.root_path.join(p)).collect())
     }
 
+    #[allow(clippy::type_complexity)]
     fn make_walker(
         &self,
         excludes: Option<Vec<PathBuf>>,
     ) -> Result<
         PagingProtoWalker<
-            impl Fn(PathBuf, Rc<HashSet<PathBuf>>) -> DeepProtoWalker + Clone,
+            impl Fn(PathBuf, PageKind, Arc<HashSet<PathBuf>>) -> DeepProtoWalker + Clone,
             DeepProtoWalker,
         >,
         GenerateError,
     > {
         let root_dir = self.root_path.clone();
-        let mut walker =
-            PagingProtoWalker::new(root_dir, |p: PathBuf, e| DeepProtoWalker::new(p, e));
+        let mut walker = PagingProtoWalker::new(root_dir, |p: PathBuf, k, e| {
+            let mut walker = DeepProtoWalker::new(p, e);
+            walker.set_recursive(k == PageKind::Directory);
+            walker
+        });
 
         if let Some(excludes) = excludes {
-            match walker.set_exclude(excludes.into_iter()) {
-                Err(e) => return Err(GenerateError::ReadDirFailed(e)),
-                _ => (),
+            if let Err(e) = walker.set_exclude(excludes.into_iter()) {
+                return Err(GenerateError::ReadDirFailed(e));
             }
         }
 
@@ -163,29 +1379,597 @@ impl Generator {
     }
 }
 
+impl fmt::Debug for Generator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Generator")
+            .field("root_path", &self.root_path)
+            .field("config", &self.config)
+            .field("locked", &self.locked)
+            .field("offline", &self.offline)
+            .field("platform", &self.platform)
+            .field("jobs", &self.jobs)
+            .field("fail_fast", &self.fail_fast)
+            .field("force", &self.force)
+            .finish()
+    }
+}
+
 impl From<DownloadError> for GenerateError {
     fn from(e: DownloadError) -> Self {
         GenerateError::NoProtoc(Box::new(e))
     }
 }
 
+impl From<SystemError> for GenerateError {
+    fn from(e: SystemError) -> Self {
+        GenerateError::NoProtoc(Box::new(e))
+    }
+}
+
+impl From<LockError> for GenerateError {
+    fn from(e: LockError) -> Self {
+        GenerateError::LockFailed(e)
+    }
+}
+
 impl From<GoError> for GenerateError {
     fn from(e: GoError) -> Self {
         GenerateError::InvocationFailed(Box::new(e))
     }
 }
 
+impl Invocation {
+    /// Returns arguments as if plugins wrote code to their outputs directly.
+    pub fn unstaged_args(&self) -> Vec<OsString> {
+        self.args
+            .iter()
+            .map(|arg| {
+                let staged = self.staged.iter().find(|s| s.path.as_os_str() == arg);
+
+                match staged {
+                    Some(stage) => stage.output.clone().into_os_string(),
+                    None => arg.clone(),
+                }
+            })
+            .collect()
+    }
+}
+
+impl From<StateError> for GenerateError {
+    fn from(e: StateError) -> Self {
+        GenerateError::StateFailed(e)
+    }
+}
+
 impl fmt::Display for GenerateError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            GenerateError::NoProtoc(e) => write!(f, "Proto compiler not found: {:?}", e),
+            GenerateError::NoProtoc(e) => write!(f, "Proto compiler not found: {}", e),
             GenerateError::ReadDirFailed(e) => write!(f, "Failed to read directory: {}", e),
             GenerateError::InvocationFailed(e) => write!(f, "Protoc invocation failed: {:?}", e),
             GenerateError::ProtocFailed(e) => write!(f, "Protoc returned error: {}", e),
+            GenerateError::CompilationFailed(failures) => {
+                write!(f, "Protoc failed on {} page(s):", failures.len())?;
+                for failure in failures {
+                    write!(f, "\n{}", failure)?;
+                }
+
+                Ok(())
+            }
+            GenerateError::LockFailed(e) => write!(f, "Lockfile error: {}", e),
+            GenerateError::StateFailed(e) => write!(f, "State file error: {}", e),
+            GenerateError::CheckFailed(e) => write!(f, "Failed to compare generated code: {}", e),
+            GenerateError::OutputFailed(e) => write!(f, "Failed to write generated code: {}", e),
+            GenerateError::InvalidHook(plugin, command) => {
+                write!(f, "Invalid post command of {}: {}", plugin, command)
+            }
+            GenerateError::HooksFailed(failures) => {
+                write!(f, "{} post command(s) failed:", failures.len())?;
+                for failure in failures {
+                    write!(f, "\n{}", failure)?;
+                }
+
+                Ok(())
+            }
+        }
+    }
+}
+
+impl fmt::Display for PageFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "  {} ({}): ",
+            self.page.display(),
+            self.plugins.join(", ")
+        )?;
+        match self.status {
+            Ok(status) => write!(f, "{}", status)?,
+            Err(ref e) => write!(f, "failed to start protoc: {}", e)?,
+        }
+
+        for diagnostic in &self.diagnostics {
+            write!(f, "\n    {}", diagnostic)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl fmt::Display for HookFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "  {}: {}: ", self.plugin, self.command)?;
+        match self.result {
+            Ok(status) => write!(f, "{}", status),
+            Err(ref e) => write!(f, "failed to start: {}", e),
         }
     }
 }
 
 impl error::Error for GenerateError {}
 
+/// Splits a post command into arguments and replaces placeholders.
+///
+/// `{output}` is replaced anywhere in arguments, while an argument `{files}`
+/// expands into paths of generated files. Returns `None` if the command is
+/// malformed.
+fn hook_args(command: &str, output: &Path, files: &[PathBuf]) -> Option<Vec<OsString>> {
+    let words = shell_words::split(command).ok()?;
+    if words.first().is_none_or(|w| w == config::Plugin::FILES) {
+        return None;
+    }
+
+    let output = output.to_string_lossy();
+    let mut args = vec![];
+    for word in words {
+        match word.as_str() {
+            config::Plugin::FILES => args.extend(files.iter().map(OsString::from)),
+            _ => args.push(word.replace(config::Plugin::OUTPUT, &output).into()),
+        }
+    }
+
+    Some(args)
+}
+
+/// Copies all files under `from` to `to` keeping their relative paths.
+fn copy_files(from: &Path, to: &Path) -> io::Result<()> {
+    for file in check::list_files(from)? {
+        let target = to.join(&file);
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        fs::copy(from.join(&file), target)?;
+    }
+
+    Ok(())
+}
+
 impl Walker for std::iter::Peekable<DeepProtoWalker> {}
+
+impl<F> Walker for std::iter::Map<std::vec::IntoIter<PathBuf>, F> where
+    F: FnMut(PathBuf) -> io::Result<PathBuf>
+{
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::os::unix::fs::PermissionsExt;
+
+    use super::*;
+
+    /// Writes what it's given to `--python_out` for every proto.
+    const PROTOC: &str = r#"#!/bin/sh
+if [ "$1" = "--version" ]; then echo "libprotoc 3.12.3"; exit 0; fi
+while [ $# -gt 0 ]; do
+  case "$1" in
+    --python_out) out="$2"; shift ;;
+    *.proto) name=$(basename "$1" .proto); echo "$name" > "$out/${name}_pb2.py" ;;
+  esac
+  shift
+done
+"#;
+
+    /// Appends content of `fmt.conf` found in a parent directory of the
+    /// plugin output to every file, like formatters looking for their config.
+    const FORMATTER: &str = r#"#!/bin/sh
+dir="$PROTOGEN_OUTPUT"
+while [ ! -f "$dir/fmt.conf" ]; do
+  [ "$dir" = / ] && exit 1
+  dir=$(dirname "$dir")
+done
+for file in "$@"; do
+  cat "$dir/fmt.conf" >> "$file"
+done
+"#;
+
+    const CONFIG: &str = r#"
+[protoc]
+version = "3.12.3"
+path = "protoc"
+
+[[plugins]]
+name = "python"
+output = "gen/py"
+post = ["./fmt.sh {files}"]
+"#;
+
+    /// Prints names of protos it's given, sleeps on slow ones and fails on
+    /// bad ones.
+    const PAGE_PROTOC: &str = r#"#!/bin/sh
+for arg in "$@"; do
+  case "$arg" in
+    *slow.proto) sleep 0.5 ;;
+  esac
+done
+for arg in "$@"; do
+  case "$arg" in
+    *bad.proto) echo "$arg:1:1: bad" >&2; exit 1 ;;
+    *.proto) basename "$arg" ;;
+  esac
+done
+"#;
+
+    fn write_script(path: &Path, content: &str) {
+        fs::write(path, content).unwrap();
+        fs::set_permissions(path, fs::Permissions::from_mode(0o755)).unwrap();
+    }
+
+    /// Creates a project compiling `protos` with `PAGE_PROTOC`.
+    fn make_page_project(prefix: &str, protos: &[&str]) -> (TempDir, Generator) {
+        let dir = TempDir::new(prefix).unwrap();
+        let root = dir.path();
+        write_script(&root.join("protoc"), PAGE_PROTOC);
+        for proto in protos {
+            let path = root.join(proto);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, "syntax = \"proto3\";\n").unwrap();
+        }
+
+        let config = "[protoc]\nversion = \"3.12.3\"\npath = \"protoc\"\n\n\
+                      [[plugins]]\nname = \"python\"\noutput = \"gen\"\n";
+        let generator = Generator::new(root, toml::from_str(config).unwrap());
+        (dir, generator)
+    }
+
+    fn page_invocations(generator: &Generator) -> Vec<Invocation> {
+        let protoc_path = generator.root_path.join("protoc");
+        let mode = OutputMode::Direct;
+        generator
+            .make_invocations(&protoc_path, None, &mode)
+            .unwrap()
+    }
+
+    #[test]
+    fn reports_outputs_in_invocation_order() {
+        let protos = ["slow.proto", "a/a.proto", "b/bad.proto", "c/c.proto"];
+        let (_dir, mut generator) = make_page_project("protogen-test-gen-order", &protos);
+        generator.set_jobs(4);
+
+        // the root page comes first and finishes last
+        let invocations = page_invocations(&generator);
+        assert_eq!(invocations[0].page, Path::new("."));
+        let outputs = generator.run(&invocations);
+
+        for (invocation, output) in invocations.iter().zip(&outputs) {
+            let output = output.as_ref().unwrap().as_ref().unwrap();
+            let name = invocation.protos[0].file_name().unwrap();
+            let printed = match output.status.success() {
+                true => &output.stdout,
+                false => &output.stderr,
+            };
+
+            let printed = String::from_utf8_lossy(printed);
+            assert!(printed.contains(name.to_str().unwrap()), "{}", printed);
+        }
+
+        let (succeeded, failures) = generator.report(&invocations, outputs);
+        let pages: Vec<_> = succeeded.iter().map(|&i| &invocations[i].page).collect();
+        let mut expected: Vec<_> = invocations.iter().map(|i| &i.page).collect();
+        expected.retain(|p| *p != Path::new("b"));
+        assert_eq!(pages, expected);
+
+        assert_eq!(failures.len(), 1);
+        assert_eq!(failures[0].page, Path::new("b"));
+        assert_eq!(failures[0].diagnostics[0].message, "bad");
+    }
+
+    #[test]
+    fn skips_invocations_after_failure_in_fail_fast_mode() {
+        let protos = ["bad.proto", "a/a.proto", "b/b.proto"];
+        let (_dir, mut generator) = make_page_project("protogen-test-gen-fail-fast", &protos);
+        generator.set_jobs(1);
+
+        let invocations = page_invocations(&generator);
+        assert_eq!(invocations[0].page, Path::new("."));
+        let outputs = generator.run(&invocations);
+        assert!(outputs.iter().all(Option::is_some));
+        let (succeeded, failures) = generator.report(&invocations, outputs);
+        assert_eq!(succeeded, [1, 2]);
+        assert_eq!(failures.len(), 1);
+
+        generator.set_fail_fast(true);
+        let outputs = generator.run(&invocations);
+        assert!(outputs[0].is_some());
+        assert!(outputs[1..].iter().all(Option::is_none));
+
+        let (succeeded, failures) = generator.report(&invocations, outputs);
+        assert!(succeeded.is_empty());
+        assert_eq!(failures.len(), 1);
+        assert_eq!(failures[0].page, Path::new("."));
+    }
+
+    #[test]
+    fn expands_descriptor_set_page() {
+        let protos = ["root.proto", "a/a.proto", "b/nested/b.proto"];
+        let (dir, mut generator) = make_page_project("protogen-test-gen-descriptor", &protos);
+        let root = dir.path();
+        let descriptor_set = DescriptorSet::new("sets/{page}/set.pb");
+        generator.config.descriptor_set = Some(descriptor_set.clone());
+
+        let path = |page| generator.descriptor_set_path(&descriptor_set, Path::new(page));
+        assert_eq!(path("."), root.join("sets/_root/set.pb"));
+        assert_eq!(path("a"), root.join("sets/a/set.pb"));
+
+        let mut invocations = page_invocations(&generator);
+        invocations.retain(|i| i.plugins == [DESCRIPTOR_SET]);
+        let mut pages: Vec<_> = invocations.iter().map(|i| i.page.clone()).collect();
+        pages.sort();
+        assert_eq!(pages, [Path::new("."), Path::new("a"), Path::new("b")]);
+
+        generator.create_output_dirs(&invocations).unwrap();
+        assert!(root.join("sets/_root").is_dir());
+        assert!(root.join("sets/b").is_dir());
+
+        // a single set isn't named after a page
+        let descriptor_set = DescriptorSet::new("set.pb");
+        assert!(!descriptor_set.per_page());
+        generator.config.descriptor_set = Some(descriptor_set);
+        let mut invocations = page_invocations(&generator);
+        invocations.retain(|i| i.plugins == [DESCRIPTOR_SET]);
+        assert_eq!(invocations.len(), 1);
+        assert_eq!(invocations[0].protos.len(), 3);
+    }
+
+    #[test]
+    fn reports_descriptor_set_directory_failure_as_output_failure() {
+        let (dir, mut generator) =
+            make_page_project("protogen-test-gen-descriptor-dir", &["a.proto"]);
+        fs::write(dir.path().join("sets"), "not a directory").unwrap();
+        let descriptor_set = DescriptorSet::new("sets/{page}.pb");
+        generator.config.descriptor_set = Some(descriptor_set);
+
+        let invocations = page_invocations(&generator);
+        match generator.create_output_dirs(&invocations) {
+            Err(GenerateError::OutputFailed(_)) => (),
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn compiles_pages_with_missing_files_again() {
+        let dir = TempDir::new("protogen-test-gen-missing").unwrap();
+        let root = dir.path();
+        write_script(&root.join("protoc"), PROTOC);
+        fs::write(root.join("a.proto"), "syntax = \"proto3\";\n").unwrap();
+
+        let config = "[protoc]\nversion = \"3.12.3\"\npath = \"protoc\"\n\n\
+                      [[plugins]]\nname = \"python\"\noutput = \"gen/py\"\n";
+        let generator = Generator::new(root, toml::from_str(config).unwrap());
+        generator.generate().unwrap();
+
+        let file = root.join("gen/py/a_pb2.py");
+        let state = State::load(&root.join(STATE_FILE));
+        assert_eq!(state.pages[0].files, [Path::new("gen/py/a_pb2.py")]);
+
+        // up to date pages aren't compiled
+        fs::write(&file, "edited\n").unwrap();
+        generator.generate().unwrap();
+        assert_eq!(fs::read_to_string(&file).unwrap(), "edited\n");
+
+        fs::remove_file(&file).unwrap();
+        generator.generate().unwrap();
+        assert_eq!(fs::read_to_string(&file).unwrap(), "a\n");
+    }
+
+    #[test]
+    fn refuses_unrecorded_archive_when_locked() {
+        let dir = TempDir::new("protogen-test-gen-locked").unwrap();
+        let root = dir.path();
+        fs::create_dir(root.join("archives")).unwrap();
+        fs::write(root.join("a.proto"), "syntax = \"proto3\";\n").unwrap();
+
+        let config = "[protoc]\nversion = \"3.12.3\"\narchives = \"archives\"\n";
+        let mut generator = Generator::new(root, toml::from_str(config).unwrap());
+        generator.set_locked(true);
+
+        match generator.generate() {
+            Err(GenerateError::LockFailed(LockError::Outdated(_))) => (),
+            other => panic!("unexpected result: {:?}", other),
+        }
+
+        assert!(!root.join(LOCK_FILE).exists());
+    }
+
+    /// Writes files into the directory of each proto relative to the root,
+    /// like protoc does for protos in packages.
+    const NESTED_PROTOC: &str = r#"#!/bin/sh
+if [ "$1" = "--version" ]; then echo "libprotoc 3.12.3"; exit 0; fi
+while [ $# -gt 0 ]; do
+  case "$1" in
+    --python_out) out="$2"; shift ;;
+    *.proto)
+      dir="$out/$(dirname "${1#"$(pwd)"/}")"; name=$(basename "$1" .proto)
+      mkdir -p "$dir"; echo "$name" > "$dir/${name}_pb2.py" ;;
+  esac
+  shift
+done
+"#;
+
+    /// Creates a project compiling `protos` with `NESTED_PROTOC` into
+    /// `gen/py`, cleaned if `clean`.
+    fn make_clean_project(prefix: &str, protos: &[&str], clean: bool) -> (TempDir, Generator) {
+        let dir = TempDir::new(prefix).unwrap();
+        let root = dir.path();
+        write_script(&root.join("protoc"), NESTED_PROTOC);
+        for proto in protos {
+            let path = root.join(proto);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, "syntax = \"proto3\";\n").unwrap();
+        }
+
+        let generator = make_clean_generator(root, clean);
+        (dir, generator)
+    }
+
+    fn make_clean_generator(root: &Path, clean: bool) -> Generator {
+        let config = format!(
+            "[protoc]\nversion = \"3.12.3\"\npath = \"protoc\"\n\n\
+             [[plugins]]\nname = \"python\"\noutput = \"gen/py\"\nclean = {}\n",
+            clean
+        );
+        Generator::new(root, toml::from_str(&config).unwrap())
+    }
+
+    fn recorded_files(root: &Path) -> Vec<PathBuf> {
+        let state = State::load(&root.join(STATE_FILE));
+        let mut files: Vec<_> = state.files().map(Path::to_owned).collect();
+        files.sort();
+        files
+    }
+
+    #[test]
+    fn removes_files_not_generated_anymore() {
+        let protos = ["a.proto", "sub/deep/b.proto"];
+        let (dir, generator) = make_clean_project("protogen-test-gen-prune", &protos, true);
+        let root = dir.path();
+        generator.generate().unwrap();
+
+        let output = root.join("gen/py");
+        assert!(output.join("sub/deep/b_pb2.py").exists());
+        fs::write(output.join("util.py"), "# util\n").unwrap();
+
+        // directories left empty are removed, but not the output
+        fs::remove_file(root.join("sub/deep/b.proto")).unwrap();
+        generator.generate().unwrap();
+        assert!(!output.join("sub").exists());
+        assert!(output.join("a_pb2.py").exists());
+        assert!(output.join("util.py").exists());
+        assert_eq!(recorded_files(root), vec![Path::new("gen/py/a_pb2.py")]);
+
+        fs::remove_file(root.join("a.proto")).unwrap();
+        fs::remove_file(output.join("util.py")).unwrap();
+        generator.generate().unwrap();
+        assert!(output.is_dir());
+        assert_eq!(fs::read_dir(&output).unwrap().count(), 0);
+        assert!(recorded_files(root).is_empty());
+    }
+
+    #[test]
+    fn keeps_records_of_files_in_outputs_not_cleaned() {
+        let protos = ["a.proto", "b.proto"];
+        let (dir, generator) = make_clean_project("protogen-test-gen-keep", &protos, true);
+        let root = dir.path();
+        generator.generate().unwrap();
+
+        let generator = make_clean_generator(root, false);
+        fs::remove_file(root.join("b.proto")).unwrap();
+        generator.generate().unwrap();
+        assert!(root.join("gen/py/b_pb2.py").exists());
+        assert_eq!(
+            recorded_files(root),
+            vec![Path::new("gen/py/a_pb2.py"), Path::new("gen/py/b_pb2.py")]
+        );
+
+        // the file is removed once the output is cleaned again
+        let generator = make_clean_generator(root, true);
+        generator.generate().unwrap();
+        assert!(!root.join("gen/py/b_pb2.py").exists());
+        assert_eq!(recorded_files(root), vec![Path::new("gen/py/a_pb2.py")]);
+    }
+
+    #[test]
+    fn checks_without_updating_lockfile() {
+        let dir = TempDir::new("protogen-test-check-locked").unwrap();
+        let root = dir.path();
+        fs::create_dir(root.join("archives")).unwrap();
+        fs::write(root.join("a.proto"), "syntax = \"proto3\";\n").unwrap();
+
+        let config = "[protoc]\nversion = \"3.12.3\"\narchives = \"archives\"\n";
+        let generator = Generator::new(root, toml::from_str(config).unwrap());
+
+        match generator.check() {
+            Err(GenerateError::LockFailed(LockError::Outdated(_))) => (),
+            other => panic!("unexpected result: {:?}", other),
+        }
+
+        assert!(!root.join(LOCK_FILE).exists());
+    }
+
+    #[test]
+    fn checks_extra_files_generated_by_protogen_only() {
+        let dir = TempDir::new("protogen-test-check-extra").unwrap();
+        let root = dir.path();
+        write_script(&root.join("protoc"), PROTOC);
+        fs::write(root.join("a.proto"), "syntax = \"proto3\";\n").unwrap();
+        fs::write(root.join("b.proto"), "syntax = \"proto3\";\n").unwrap();
+
+        let config = "[protoc]\nversion = \"3.12.3\"\npath = \"protoc\"\n\n\
+                      [[plugins]]\nname = \"python\"\noutput = \"gen/py\"\n";
+        let generator = Generator::new(root, toml::from_str(config).unwrap());
+        generator.generate().unwrap();
+
+        // b_pb2.py is recorded and old_pb2.py named like generated code, while
+        // util.py is written by hand
+        let output = root.join("gen/py");
+        fs::remove_file(root.join("b.proto")).unwrap();
+        fs::write(output.join("old_pb2.py"), "old\n").unwrap();
+        fs::write(output.join("util.py"), "# util\n").unwrap();
+
+        let drifts = generator.check().unwrap();
+        let paths: Vec<_> = drifts.iter().map(Drift::path).collect();
+        assert_eq!(
+            paths,
+            vec![Path::new("gen/py/b_pb2.py"), Path::new("gen/py/old_pb2.py")]
+        );
+        assert!(drifts.iter().all(|d| matches!(d, Drift::Extra { .. })));
+    }
+
+    #[test]
+    fn checks_with_post_commands_seeing_project_config() {
+        let dir = TempDir::new("protogen-test-check-post").unwrap();
+        let root = dir.path();
+        write_script(&root.join("protoc"), PROTOC);
+        write_script(&root.join("fmt.sh"), FORMATTER);
+        fs::write(root.join("fmt.conf"), "# formatted\n").unwrap();
+        fs::write(root.join("a.proto"), "syntax = \"proto3\";\n").unwrap();
+
+        let config: Config = toml::from_str(CONFIG).unwrap();
+        let generator = Generator::new(root, config);
+        generator.generate().unwrap();
+
+        let generated = fs::read_to_string(root.join("gen/py/a_pb2.py")).unwrap();
+        assert_eq!(generated, "a\n# formatted\n");
+
+        let drifts = generator.check().unwrap();
+        assert!(drifts.is_empty(), "unexpected drifts: {:?}", drifts);
+
+        // nothing but the state is added to the root
+        let mut files: Vec<_> = fs::read_dir(root)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .collect();
+        files.sort();
+        assert_eq!(
+            files,
+            [
+                ".protogen-state",
+                "a.proto",
+                "fmt.conf",
+                "fmt.sh",
+                "gen",
+                "protoc"
+            ]
+        );
+    }
+}
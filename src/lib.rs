@@ -1,4 +1,10 @@
+pub mod check;
 pub mod config;
+pub mod fingerprint;
 pub mod gen;
+pub mod lock;
 pub mod protoc;
+pub mod state;
+pub mod temp;
 pub mod walk;
+pub mod watch;
@@ -0,0 +1,124 @@
+use std::{error, fmt, fs, io, path::Path};
+
+use semver::Version;
+use serde::{Deserialize, Serialize};
+
+/// Name of the lockfile placed next to `protogen.toml`.
+pub const LOCK_FILE: &str = "protogen.lock";
+
+#[derive(Debug)]
+pub enum LockError {
+    Io(io::Error),
+    Parse(toml::de::Error),
+    Serialize(toml::ser::Error),
+    /// The lockfile must be updated but that's not allowed.
+    Outdated(String),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Lockfile {
+    pub protoc: Option<ProtocLock>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ProtocLock {
+    pub version: Version,
+    #[serde(default)]
+    pub assets: Vec<AssetLock>,
+}
+
+/// Release archive of protoc for a single platform.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct AssetLock {
+    pub platform: String,
+    pub name: String,
+    pub sha256: String,
+}
+
+impl Lockfile {
+    /// Reads a lockfile at `path` or returns an empty one if it doesn't exist.
+    pub fn load(path: &Path) -> Result<Self, LockError> {
+        if !path.exists() {
+            return Ok(Lockfile::default());
+        }
+
+        let buf = fs::read(path)?;
+        let lock = toml::from_slice(&buf)?;
+        Ok(lock)
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), LockError> {
+        let mut content = String::from("# This file is generated by protogen. Do not edit it.\n\n");
+        content.push_str(&toml::to_string(self)?);
+        fs::write(path, content)?;
+        Ok(())
+    }
+
+    /// Returns the pinned protoc version.
+    pub fn protoc_version(&self) -> Option<&Version> {
+        self.protoc.as_ref().map(|p| &p.version)
+    }
+
+    pub fn protoc_asset(&self, version: &Version, platform: &str) -> Option<&AssetLock> {
+        let protoc = self.protoc.as_ref()?;
+        if &protoc.version != version {
+            return None;
+        }
+
+        protoc.assets.iter().find(|a| a.platform == platform)
+    }
+
+    /// Records an archive for the protoc `version`.
+    ///
+    /// Archives of other protoc versions are dropped.
+    pub fn set_protoc_asset(&mut self, version: &Version, asset: AssetLock) {
+        match self.protoc {
+            Some(ref protoc) if &protoc.version == version => (),
+            _ => {
+                self.protoc = Some(ProtocLock {
+                    version: version.clone(),
+                    assets: vec![],
+                })
+            }
+        }
+
+        let protoc = self.protoc.as_mut().expect("protoc lock is set");
+        protoc.assets.retain(|a| a.platform != asset.platform);
+        protoc.assets.push(asset);
+        protoc.assets.sort_by(|a, b| a.platform.cmp(&b.platform));
+    }
+}
+
+impl From<io::Error> for LockError {
+    fn from(e: io::Error) -> Self {
+        LockError::Io(e)
+    }
+}
+
+impl From<toml::de::Error> for LockError {
+    fn from(e: toml::de::Error) -> Self {
+        LockError::Parse(e)
+    }
+}
+
+impl From<toml::ser::Error> for LockError {
+    fn from(e: toml::ser::Error) -> Self {
+        LockError::Serialize(e)
+    }
+}
+
+impl fmt::Display for LockError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LockError::Io(e) => e.fmt(f),
+            LockError::Parse(e) => write!(f, "failed to parse lockfile: {}", e),
+            LockError::Serialize(e) => write!(f, "failed to serialize lockfile: {}", e),
+            LockError::Outdated(e) => write!(f, "lockfile needs to be updated: {}", e),
+        }
+    }
+}
+
+impl error::Error for LockError {}
@@ -1,36 +1,419 @@
 use std::{
+    collections::BTreeSet,
+    env,
     fs::File,
-    io::Read,
+    io::{self, IsTerminal, Read, Write},
     path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::SystemTime,
 };
 
 use anyhow::{self, Context};
+use log::LevelFilter;
+use semver::Version;
 use structopt::StructOpt;
-use toml;
 
-use protogen::{config, gen};
+use protogen::{
+    config, gen,
+    protoc::provider::{caches_path, DownloadProgress, ProtocCache},
+    watch::Watcher,
+};
 
 #[derive(Debug, StructOpt)]
 #[structopt(name = "protogen")]
 struct Args {
-    #[structopt(short, long)]
-    verbose: bool,
+    /// Log more details, repeat for even more (-vv)
+    #[structopt(short, long, parse(from_occurrences))]
+    verbose: u8,
+
+    /// Log errors only
+    #[structopt(short, long, conflicts_with = "verbose")]
+    quiet: bool,
 
     #[structopt(short, long, parse(from_os_str), default_value = "protogen.toml")]
     config: PathBuf,
+
+    /// Refuse to update protogen.lock
+    #[structopt(long)]
+    locked: bool,
+
+    /// Don't access the network, use cached or local protoc archives only
+    #[structopt(long)]
+    offline: bool,
+
+    /// Download protoc for this platform instead of the current one, e.g. linux-aarch_64
+    #[structopt(long, env = "PROTOGEN_PLATFORM")]
+    platform: Option<String>,
+
+    /// Number of protoc invocations to run in parallel, defaults to the number of CPUs
+    #[structopt(short, long)]
+    jobs: Option<usize>,
+
+    /// Stop after the first failed protoc invocation instead of running all of them
+    #[structopt(long)]
+    fail_fast: bool,
+
+    /// Compile all pages even if they are up to date
+    #[structopt(long)]
+    force: bool,
+
+    /// Write descriptor set of all protos to this file, `{page}` in the name writes one per page
+    #[structopt(long, parse(from_os_str))]
+    descriptor_set_out: Option<PathBuf>,
+
+    /// Print protoc invocations instead of running them
+    #[structopt(long)]
+    dry_run: bool,
+
+    #[structopt(subcommand)]
+    command: Option<Command>,
 }
 
+#[derive(Debug, StructOpt)]
+enum Command {
+    /// Manage protoc releases downloaded into the cache
+    Cache(CacheCommand),
+    /// Fail if generated code differs from code in plugin outputs
+    Check,
+    /// Print protoc invocations without running them
+    PrintCommands {
+        /// Print invocations as JSON
+        #[structopt(long)]
+        json: bool,
+    },
+    /// Regenerate code whenever protos or the config change
+    Watch,
+}
+
+#[derive(Debug, StructOpt)]
+enum CacheCommand {
+    /// List cached protoc versions
+    List,
+    /// Remove all but the most recently used versions
+    Prune {
+        /// Number of installations to keep, every platform counts separately
+        #[structopt(long, default_value = "1")]
+        keep: usize,
+    },
+    /// Remove cached protoc version for all platforms
+    Remove {
+        #[structopt(parse(try_from_str = config::parse_version))]
+        version: Version,
+    },
+    /// Download protoc version into the cache
+    Fetch {
+        #[structopt(parse(try_from_str = config::parse_version))]
+        version: Version,
+    },
+}
+
+/// Environment variable with log filters, e.g. `protogen=debug`.
+const LOG_ENV: &str = "PROTOGEN_LOG";
+
 fn main() -> anyhow::Result<()> {
     let args = Args::from_args();
-    let config_path = root_dir(&args.config)?;
-    let config = parse_config(&args.config)?;
+    init_logger(&args);
 
-    let generator = gen::Generator::new(config_path, config);
+    match args.command {
+        Some(Command::Cache(ref command)) => cache(&args, command),
+        Some(Command::Check) => check(&args),
+        Some(Command::PrintCommands { json }) => print_commands(&args, json),
+        Some(Command::Watch) => watch(&args),
+        None if args.dry_run => print_commands(&args, false),
+        None => generate(&args),
+    }
+}
+
+fn generate(args: &Args) -> anyhow::Result<()> {
+    let config = parse_config(&args.config)?;
+    let generator = make_generator(args, config)?;
     generator.generate()?;
 
     Ok(())
 }
 
+fn check(args: &Args) -> anyhow::Result<()> {
+    let config = parse_config(&args.config)?;
+    let generator = make_generator(args, config)?;
+    let drifts = generator.check()?;
+    if drifts.is_empty() {
+        return Ok(());
+    }
+
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+    for drift in &drifts {
+        write!(stdout, "{}", drift)?;
+    }
+
+    anyhow::bail!("{} generated file(s) are out of date", drifts.len())
+}
+
+fn watch(args: &Args) -> anyhow::Result<()> {
+    let config_path = args
+        .config
+        .canonicalize()
+        .with_context(|| format!("failed to open {:?}", args.config))?;
+    let root_path = root_dir(&config_path)?;
+
+    // only the first run compiles everything, fingerprints tell what changed
+    // afterwards
+    let mut force = args.force;
+
+    loop {
+        // the generator is rebuilt whenever the config changes
+        let mut generator = parse_config(&args.config).and_then(|c| make_generator(args, c));
+        let mut watcher = match generator {
+            Ok(ref mut generator) => {
+                generator.set_force(force);
+                run_watched(generator);
+                generator.set_force(false);
+                force = false;
+                generator.watcher()
+            }
+            Err(ref e) => {
+                eprintln!("Error: {:?}", e);
+                Watcher::new(root_path.clone())
+            }
+        };
+
+        watcher.add_file(&config_path);
+        eprintln!("Watching for changes in {}", root_path.display());
+
+        loop {
+            let changed = match watcher.wait() {
+                Ok(changed) => changed,
+                Err(e) => {
+                    // e.g. a directory removed while it was scanned
+                    eprintln!("Error: failed to scan {}: {}", root_path.display(), e);
+                    continue;
+                }
+            };
+
+            if changed.contains(&config_path) {
+                eprintln!("Config changed, reloading");
+                break;
+            }
+
+            let generator = match generator {
+                Ok(ref generator) => generator,
+                Err(_) => continue,
+            };
+
+            let pages: BTreeSet<_> = changed
+                .iter()
+                .filter_map(|p| generator.page_of(p))
+                .collect();
+            if pages.is_empty() {
+                continue;
+            }
+
+            // pages are named for the user only, fingerprints select what's
+            // compiled, including pages importing a changed proto
+            let pages: Vec<_> = pages.iter().map(|p| p.display().to_string()).collect();
+            eprintln!("Changed {}, regenerating outdated pages", pages.join(", "));
+            run_watched(generator);
+        }
+    }
+}
+
+/// Generates code printing errors instead of stopping the watch.
+fn run_watched(generator: &gen::Generator) {
+    match generator.generate() {
+        Ok(()) => eprintln!("Generated successfully"),
+        Err(e) => eprintln!("Error: {}", e),
+    }
+}
+
+fn print_commands(args: &Args, json: bool) -> anyhow::Result<()> {
+    let config = parse_config(&args.config)?;
+    let generator = make_generator(args, config)?;
+    let invocations = generator.commands()?;
+
+    if json {
+        let invocations: Vec<_> = invocations
+            .iter()
+            .map(|i| {
+                serde_json::json!({
+                    "plugins": i.plugins,
+                    "page": i.page,
+                    "working_dir": i.working_dir,
+                    "args": i.args.iter().map(|a| a.to_string_lossy()).collect::<Vec<_>>(),
+                })
+            })
+            .collect();
+
+        println!("{}", serde_json::to_string_pretty(&invocations)?);
+        return Ok(());
+    }
+
+    for invocation in invocations {
+        let args: Vec<_> = invocation
+            .args
+            .iter()
+            .map(|a| a.to_string_lossy().into_owned())
+            .collect();
+
+        let plugins = invocation.plugins.join(", ");
+        println!("# {} ({})", invocation.page.display(), plugins);
+        println!(
+            "cd {} && {}",
+            shell_words::quote(&invocation.working_dir.to_string_lossy()),
+            shell_words::join(args)
+        );
+    }
+
+    Ok(())
+}
+
+fn cache(args: &Args, command: &CacheCommand) -> anyhow::Result<()> {
+    let caches_path = caches_path().context("can't find cache directory")?;
+    let cache = ProtocCache::new(caches_path);
+
+    match command {
+        CacheCommand::List => {
+            let cached = cache.list()?;
+            if cached.is_empty() {
+                eprintln!("No cached protoc versions in {:?}", cache.caches_path());
+                return Ok(());
+            }
+
+            println!(
+                "{:<12} {:<22} {:>10}  LAST USED",
+                "VERSION", "PLATFORM", "SIZE"
+            );
+            for protoc in cached {
+                let mut version = format!("v{}", protoc.version);
+                if !protoc.installed {
+                    version.push('*');
+                }
+
+                println!(
+                    "{:<12} {:<22} {:>10}  {}",
+                    version,
+                    protoc.platform.as_deref().unwrap_or("-"),
+                    format_size(protoc.size),
+                    format_last_used(protoc.last_used)
+                );
+            }
+        }
+        CacheCommand::Prune { keep } => {
+            for protoc in cache.prune(*keep)? {
+                match protoc.platform {
+                    Some(platform) => {
+                        eprintln!("Removed protoc v{} for {}", protoc.version, platform)
+                    }
+                    None => eprintln!("Removed protoc v{}", protoc.version),
+                }
+            }
+        }
+        CacheCommand::Remove { version } => {
+            if !cache.remove(version)? {
+                anyhow::bail!("protoc v{} isn't cached", version);
+            }
+
+            eprintln!("Removed protoc v{}", version);
+        }
+        CacheCommand::Fetch { version } => {
+            // mirror and archive settings are used if there is a config
+            let mut config = match args.config.is_file() {
+                true => parse_config(&args.config)?,
+                false => config::Config {
+                    protoc: config::Protoc::new(config::ProtocVersion::Exact(version.clone())),
+                    plugins: vec![],
+                    descriptor_set: None,
+                },
+            };
+
+            config.protoc.version = config::ProtocVersion::Exact(version.clone());
+            let generator = make_generator(args, config)?;
+            let archive = generator.fetch_protoc(version)?;
+            eprintln!("Fetched protoc v{} from {}", version, archive.name);
+        }
+    }
+
+    Ok(())
+}
+
+fn init_logger(args: &Args) {
+    let level = match (args.quiet, args.verbose) {
+        (true, _) => LevelFilter::Error,
+        (false, 0) => LevelFilter::Warn,
+        (false, 1) => LevelFilter::Info,
+        (false, 2) => LevelFilter::Debug,
+        (false, _) => LevelFilter::Trace,
+    };
+
+    let mut builder = env_logger::Builder::new();
+    builder.filter_level(LevelFilter::Warn);
+    builder.filter_module("protogen", level);
+    builder.format_timestamp(None);
+
+    // explicit filters take precedence over flags
+    if let Ok(filters) = env::var(LOG_ENV) {
+        builder.parse_filters(&filters);
+    }
+
+    builder.init();
+}
+
+fn make_generator(args: &Args, mut config: config::Config) -> anyhow::Result<gen::Generator> {
+    if let Some(ref output) = args.descriptor_set_out {
+        // unlike the config, the flag is relative to the working directory
+        let output = env::current_dir()?.join(output);
+        match config.descriptor_set {
+            Some(ref mut descriptor_set) => descriptor_set.output = output,
+            None => config.descriptor_set = Some(config::DescriptorSet::new(output)),
+        }
+    }
+
+    let root_path = match args.config.is_file() {
+        true => root_dir(&args.config)?,
+        false => env::current_dir()?,
+    };
+
+    let mut generator = gen::Generator::new(root_path, config);
+    generator.set_locked(args.locked);
+    generator.set_offline(args.offline);
+    generator.set_fail_fast(args.fail_fast);
+    generator.set_force(args.force);
+    if let Some(jobs) = args.jobs {
+        generator.set_jobs(jobs);
+    }
+
+    if let Some(ref platform) = args.platform {
+        generator.set_platform(platform.clone());
+    }
+
+    if io::stderr().is_terminal() && !args.quiet {
+        generator.set_progress(Arc::new(ProgressBar::default()));
+    }
+
+    Ok(generator)
+}
+
+fn format_size(bytes: u64) -> String {
+    match bytes {
+        0..=1023 => format!("{} B", bytes),
+        1024..=1_048_575 => format!("{:.1} KiB", bytes as f64 / 1024.0),
+        _ => format!("{:.1} MiB", bytes as f64 / (1024.0 * 1024.0)),
+    }
+}
+
+fn format_last_used(time: Option<SystemTime>) -> String {
+    let elapsed = match time.and_then(|t| SystemTime::now().duration_since(t).ok()) {
+        Some(elapsed) => elapsed.as_secs(),
+        None => return "unknown".to_owned(),
+    };
+
+    match elapsed {
+        0..=59 => "just now".to_owned(),
+        60..=3599 => format!("{} minutes ago", elapsed / 60),
+        3600..=86399 => format!("{} hours ago", elapsed / 3600),
+        _ => format!("{} days ago", elapsed / 86400),
+    }
+}
+
 fn parse_config(path: &Path) -> anyhow::Result<config::Config> {
     let mut file = File::open(path).with_context(|| format!("failed to open {:?}", path))?;
     let mut buf = vec![];
@@ -41,14 +424,53 @@ fn parse_config(path: &Path) -> anyhow::Result<config::Config> {
 }
 
 fn root_dir(path: &Path) -> anyhow::Result<PathBuf> {
-    if path.is_absolute() {
-        return Ok(path.into());
+    let mut root = env::current_dir()?.join(path).canonicalize()?;
+    root.pop();
+    Ok(root)
+}
+
+/// Renders download progress to stderr.
+#[derive(Default)]
+struct ProgressBar {
+    state: Mutex<(String, Option<u64>)>,
+}
+
+impl ProgressBar {
+    const WIDTH: usize = 30;
+}
+
+impl DownloadProgress for ProgressBar {
+    fn start(&self, name: &str, total: Option<u64>) {
+        let mut state = self.state.lock().unwrap();
+        *state = (name.to_owned(), total);
     }
 
-    let mut cwd = std::env::current_dir()?;
-    cwd.push(path);
+    fn update(&self, downloaded: u64) {
+        let state = self.state.lock().unwrap();
+        let (ref name, total) = *state;
+        let mib = |bytes: u64| bytes as f64 / (1024.0 * 1024.0);
 
-    let mut root = cwd.canonicalize()?;
-    root.pop();
-    Ok(root)
+        let line = match total {
+            Some(total) if total > 0 => {
+                let filled = (downloaded.min(total) * Self::WIDTH as u64 / total) as usize;
+                format!(
+                    "Downloading {} [{}{}] {:.1}/{:.1} MiB",
+                    name,
+                    "#".repeat(filled),
+                    " ".repeat(Self::WIDTH - filled),
+                    mib(downloaded),
+                    mib(total)
+                )
+            }
+            _ => format!("Downloading {} {:.1} MiB", name, mib(downloaded)),
+        };
+
+        let mut stderr = io::stderr();
+        let _ = write!(stderr, "\r{}", line);
+        let _ = stderr.flush();
+    }
+
+    fn finish(&self) {
+        eprintln!();
+    }
 }
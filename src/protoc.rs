@@ -1,2 +1,4 @@
+pub mod args_file;
 pub mod compiler;
+pub mod diagnostic;
 pub mod provider;
@@ -0,0 +1,133 @@
+use std::{
+    env,
+    ffi::OsString,
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    process,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+/// Length of a command line beyond which arguments are passed via file.
+///
+/// It's well below Windows' limit of 32K characters and Linux' limit of a
+/// single argument.
+pub const THRESHOLD: usize = 16 * 1024;
+
+static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// Temporary response file with protoc arguments, one per line.
+///
+/// Protoc reads arguments from it if invoked with `@<path>`. The file is
+/// removed on drop.
+#[derive(Debug)]
+pub struct ArgsFile {
+    path: PathBuf,
+}
+
+impl ArgsFile {
+    /// Writes `args` to a new file in the temporary directory.
+    pub fn create(args: &[OsString]) -> io::Result<Self> {
+        let mut content = String::new();
+        for arg in args {
+            let arg = match arg.to_str() {
+                Some(arg) if !arg.contains('\n') => arg,
+                _ => {
+                    let message = format!("argument can't be written to a file: {:?}", arg);
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput, message));
+                }
+            };
+
+            content.push_str(arg);
+            content.push('\n');
+        }
+
+        let name = format!(
+            "protogen-{}-{}.args",
+            process::id(),
+            COUNTER.fetch_add(1, Ordering::SeqCst)
+        );
+
+        let path = env::temp_dir().join(name);
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)?;
+
+        // the file is removed even if writing fails
+        let args_file = Self { path };
+        file.write_all(content.as_bytes())?;
+        Ok(args_file)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Returns argument which makes protoc read the file.
+    pub fn arg(&self) -> OsString {
+        let mut arg = OsString::from("@");
+        arg.push(&self.path);
+        arg
+    }
+}
+
+impl Drop for ArgsFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Returns length of the command line made of `args`.
+pub fn command_len(args: &[OsString]) -> usize {
+    args.iter().map(|a| a.len() + 1).sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<OsString> {
+        args.iter().map(OsString::from).collect()
+    }
+
+    #[test]
+    fn writes_argument_per_line() {
+        let file = ArgsFile::create(&args(&["-I", "my protos", "--python_out=gen"])).unwrap();
+        let content = fs::read_to_string(file.path()).unwrap();
+        assert_eq!(content, "-I\nmy protos\n--python_out=gen\n");
+
+        let mut expected = OsString::from("@");
+        expected.push(file.path());
+        assert_eq!(file.arg(), expected);
+    }
+
+    #[test]
+    fn removes_file_on_drop() {
+        let file = ArgsFile::create(&args(&["a.proto"])).unwrap();
+        let path = file.path().to_owned();
+        assert!(path.is_file());
+
+        drop(file);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn refuses_newline_in_argument() {
+        let error = ArgsFile::create(&args(&["a.proto", "b\nc.proto"])).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn measures_command_line() {
+        assert_eq!(command_len(&args(&[])), 0);
+        assert_eq!(command_len(&args(&["protoc", "a.proto"])), 15);
+
+        // every argument is followed by a separator
+        let mut long = vec![OsString::from("p".repeat(THRESHOLD - 1))];
+        assert_eq!(command_len(&long), THRESHOLD);
+
+        long.push("a.proto".into());
+        assert!(command_len(&long) > THRESHOLD);
+    }
+}
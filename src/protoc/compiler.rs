@@ -1,12 +1,17 @@
+pub mod builtin;
+pub mod descriptor;
 pub mod go;
 pub mod plain;
 
+pub use builtin::Builtin;
+pub use descriptor::DescriptorSetCompiler;
 pub use go::GoCompiler;
 pub use plain::PlainCompiler;
 
 use std::{
+    error,
     ffi::OsString,
-    io,
+    fmt, io,
     path::{Path, PathBuf},
 };
 
@@ -22,6 +27,7 @@ pub trait Compiler: Clone {
 pub enum AnyCompiler {
     Plain(PlainCompiler),
     Go(GoCompiler),
+    DescriptorSet(DescriptorSetCompiler),
 }
 
 impl Compiler for AnyCompiler {
@@ -29,6 +35,7 @@ impl Compiler for AnyCompiler {
         match self {
             AnyCompiler::Plain(c) => c.add_include(path),
             AnyCompiler::Go(c) => c.add_include(path),
+            AnyCompiler::DescriptorSet(c) => c.add_include(path),
         }
     }
 
@@ -36,6 +43,7 @@ impl Compiler for AnyCompiler {
         match self {
             AnyCompiler::Plain(c) => c.set_protos(protos),
             AnyCompiler::Go(c) => c.set_protos(protos),
+            AnyCompiler::DescriptorSet(c) => c.set_protos(protos),
         }
     }
 
@@ -43,25 +51,37 @@ impl Compiler for AnyCompiler {
         match self {
             AnyCompiler::Plain(c) => c.command(),
             AnyCompiler::Go(c) => c.command(),
+            AnyCompiler::DescriptorSet(c) => c.command(),
         }
     }
 }
 
+#[derive(Debug)]
+pub enum PluginError {
+    /// Option is not supported by the builtin generator.
+    UnknownOption(Builtin, String),
+}
+
 #[derive(Debug, Clone)]
 pub struct Plugin {
     name: String,
     path: Option<PathBuf>,
     output: PathBuf,
+    redirect: Option<PathBuf>,
     options: Vec<String>,
+    builtin: Option<Builtin>,
 }
 
 impl Plugin {
     pub fn new(name: String, output: PathBuf) -> Self {
+        let builtin = name.parse().ok();
         Self {
             name,
             path: None,
             output,
+            redirect: None,
             options: vec![],
+            builtin,
         }
     }
 
@@ -69,36 +89,78 @@ impl Plugin {
         &self.name
     }
 
+    /// Returns the protoc generator if the plugin is built into protoc.
+    pub fn builtin(&self) -> Option<Builtin> {
+        self.builtin
+    }
+
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
     pub fn output(&self) -> &Path {
         &self.output
     }
 
+    /// Makes the plugin write code to `dir` instead of its output.
+    ///
+    /// The output is still used to derive e.g. Go import paths.
+    pub fn redirect<P: Into<PathBuf>>(&mut self, dir: P) {
+        self.redirect = Some(dir.into());
+    }
+
     pub fn add_option<O: Into<String>>(&mut self, option: O) {
         self.options.push(option.into());
     }
 
+    /// Sets path to the plugin binary.
+    ///
+    /// Plugin with explicit path is never treated as builtin.
     pub fn set_path<P: Into<PathBuf>>(&mut self, path: P) {
         self.path = Some(path.into());
+        self.builtin = None;
+    }
+
+    /// Checks options of builtin generators.
+    ///
+    /// Returns options which aren't known to the generator. They may be
+    /// supported by a newer protoc, so it's up to protoc to reject them.
+    pub fn validate(&self) -> Vec<PluginError> {
+        let builtin = match self.builtin {
+            Some(builtin) => builtin,
+            None => return vec![],
+        };
+
+        self.options
+            .iter()
+            .filter(|o| !builtin.is_valid_option(o))
+            .map(|o| PluginError::UnknownOption(builtin, o.clone()))
+            .collect()
     }
 
     pub fn args(self) -> Vec<OsString> {
-        let mut plugin = OsString::new();
+        let mut args = vec![];
+
+        if self.builtin.is_none() {
+            let mut plugin = OsString::new();
+            plugin.push(format!("protoc-gen-{}", self.name));
+            if let Some(path) = self.path {
+                plugin.push("=");
+                plugin.push(path);
+            }
 
-        plugin.push(format!("protoc-gen-{}", self.name));
-        if let Some(path) = self.path {
-            plugin.push("=");
-            plugin.push(path);
+            args.push(OsString::from("--plugin"));
+            args.push(plugin);
         }
 
-        let mut args = vec![
-            OsString::from("--plugin"),
-            plugin,
-            format!("--{}_out", self.name).into(),
-            self.output.into_os_string(),
-        ];
+        args.push(format!("--{}_out", self.name).into());
+        args.push(self.redirect.unwrap_or(self.output).into_os_string());
 
-        args.push(format!("--{}_opt", self.name).into());
-        args.push(self.options.join(",").into());
+        // some plugins choke on an empty parameter
+        if !self.options.is_empty() {
+            args.push(format!("--{}_opt", self.name).into());
+            args.push(self.options.join(",").into());
+        }
 
         args
     }
@@ -112,7 +174,7 @@ impl From<config::Plugin> for Plugin {
         }
 
         if let Some(options) = p.options.as_ref() {
-            for option in options.split(",") {
+            for option in options.split(',').filter(|o| !o.is_empty()) {
                 plugin.add_option(option);
             }
         }
@@ -126,3 +188,116 @@ impl From<&config::Plugin> for Plugin {
         p.clone().into()
     }
 }
+
+impl fmt::Display for PluginError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PluginError::UnknownOption(builtin, option) => {
+                write!(
+                    f,
+                    "unknown option '{}' of builtin {} generator",
+                    option, builtin
+                )?;
+                match builtin.options() {
+                    [] => write!(f, ", it isn't known to take options"),
+                    options => write!(f, ", known options are: {}", options.join(", ")),
+                }
+            }
+        }
+    }
+}
+
+impl error::Error for PluginError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(plugin: Plugin) -> Vec<String> {
+        plugin
+            .args()
+            .into_iter()
+            .map(|a| a.into_string().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn invokes_builtin_generators_without_plugin() {
+        let plugin = Plugin::new("python".to_owned(), "gen/py".into());
+        assert_eq!(plugin.builtin(), Some(Builtin::Python));
+        assert_eq!(args(plugin), ["--python_out", "gen/py"]);
+
+        let mut plugin = Plugin::new("cpp".to_owned(), "gen/cpp".into());
+        plugin.add_option("lite");
+        plugin.add_option("speed");
+        assert_eq!(
+            args(plugin),
+            ["--cpp_out", "gen/cpp", "--cpp_opt", "lite,speed"]
+        );
+    }
+
+    #[test]
+    fn invokes_external_plugins() {
+        let plugin = Plugin::new("grpc-web".to_owned(), "gen/web".into());
+        assert_eq!(plugin.builtin(), None);
+        assert_eq!(
+            args(plugin),
+            [
+                "--plugin",
+                "protoc-gen-grpc-web",
+                "--grpc-web_out",
+                "gen/web"
+            ]
+        );
+
+        // a builtin name with explicit path is an external plugin
+        let mut plugin = Plugin::new("python".to_owned(), "gen/py".into());
+        plugin.set_path("tools/protoc-gen-python");
+        plugin.add_option("pyi_out");
+        plugin.redirect("stage/0");
+        assert_eq!(plugin.builtin(), None);
+        assert_eq!(
+            args(plugin),
+            [
+                "--plugin",
+                "protoc-gen-python=tools/protoc-gen-python",
+                "--python_out",
+                "stage/0",
+                "--python_opt",
+                "pyi_out"
+            ]
+        );
+    }
+
+    #[test]
+    fn omits_empty_options() {
+        let config: config::Plugin = toml::from_str(
+            r#"
+            name = "go"
+            output = "gen/go"
+            options = ""
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            args(config.into()),
+            ["--plugin", "protoc-gen-go", "--go_out", "gen/go"]
+        );
+    }
+
+    #[test]
+    fn reports_unknown_builtin_options() {
+        let mut plugin = Plugin::new("java".to_owned(), "gen/java".into());
+        plugin.add_option("lite");
+        plugin.add_option("speed");
+        let errors: Vec<_> = plugin.validate().iter().map(ToString::to_string).collect();
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].starts_with("unknown option 'speed' of builtin java generator"));
+
+        let mut plugin = Plugin::new("java".to_owned(), "gen/java".into());
+        plugin.set_path("protoc-gen-java");
+        plugin.add_option("speed");
+        assert!(plugin.validate().is_empty());
+    }
+}
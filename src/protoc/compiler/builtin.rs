@@ -0,0 +1,139 @@
+use std::{fmt, str::FromStr};
+
+/// Code generator built into protoc.
+///
+/// Builtins are invoked with `--<name>_out` only, without `--plugin`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Builtin {
+    Cpp,
+    Java,
+    Kotlin,
+    Python,
+    Pyi,
+    Csharp,
+    Objc,
+    Php,
+    Ruby,
+}
+
+impl Builtin {
+    pub fn name(self) -> &'static str {
+        match self {
+            Builtin::Cpp => "cpp",
+            Builtin::Java => "java",
+            Builtin::Kotlin => "kotlin",
+            Builtin::Python => "python",
+            Builtin::Pyi => "pyi",
+            Builtin::Csharp => "csharp",
+            Builtin::Objc => "objc",
+            Builtin::Php => "php",
+            Builtin::Ruby => "ruby",
+        }
+    }
+
+    /// Returns names of options the generator is known to accept.
+    ///
+    /// Newer protoc releases may accept more, so other options are only
+    /// warned about.
+    pub fn options(self) -> &'static [&'static str] {
+        match self {
+            Builtin::Cpp => &[
+                "dllexport_decl",
+                "bootstrap",
+                "descriptor_table",
+                "inject_field_listener_events",
+                "safe_boundary_check",
+                "annotate_headers",
+                "annotation_pragma_name",
+                "annotation_guard_name",
+                "speed",
+                "code_size",
+                "lite",
+                "lite_implicit_weak_fields",
+                "num_cc_files",
+                "annotate_accessor",
+                "proto_h",
+                "experimental_strip_nonfunctional_codegen",
+            ],
+            Builtin::Java | Builtin::Kotlin => &[
+                "lite",
+                "shared",
+                "immutable",
+                "mutable",
+                "annotate_code",
+                "annotation_list_file",
+                "experimental_strip_nonfunctional_codegen",
+            ],
+            Builtin::Python => &[
+                "cpp_generated_lib_linked",
+                "pyi_out",
+                "annotate_code",
+                "experimental_strip_nonfunctional_codegen",
+            ],
+            Builtin::Pyi => &["annotate_code", "experimental_strip_nonfunctional_codegen"],
+            Builtin::Csharp => &[
+                "file_extension",
+                "base_namespace",
+                "internal_access",
+                "serializable",
+                "experimental_strip_nonfunctional_codegen",
+            ],
+            Builtin::Objc => &[
+                "expected_prefixes_path",
+                "expected_prefixes_suppressions",
+                "prefixes_must_be_registered",
+                "require_prefixes",
+                "generate_for_named_framework",
+                "named_framework_to_proto_path_mappings_path",
+                "runtime_import_prefix",
+                "package_to_prefix_mappings_path",
+                "use_package_as_prefix",
+                "proto_package_prefix_exceptions_path",
+                "headers_use_forward_declarations",
+                "strip_custom_options",
+                "generate_minimal_imports",
+                "experimental_strip_nonfunctional_codegen",
+            ],
+            Builtin::Php => &[
+                "aggregate_metadata",
+                "internal",
+                "internal_generate_c_wkt",
+                "experimental_strip_nonfunctional_codegen",
+            ],
+            Builtin::Ruby => &[],
+        }
+    }
+
+    /// Checks that `option` is known to the generator.
+    ///
+    /// Only the name is checked, a value after `=` is left to protoc.
+    pub fn is_valid_option(self, option: &str) -> bool {
+        let name = option.split('=').next().unwrap_or(option);
+        self.options().contains(&name)
+    }
+}
+
+impl FromStr for Builtin {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "cpp" => Ok(Builtin::Cpp),
+            "java" => Ok(Builtin::Java),
+            "kotlin" => Ok(Builtin::Kotlin),
+            "python" => Ok(Builtin::Python),
+            "pyi" => Ok(Builtin::Pyi),
+            "csharp" => Ok(Builtin::Csharp),
+            "objc" => Ok(Builtin::Objc),
+            "php" => Ok(Builtin::Php),
+            "ruby" => Ok(Builtin::Ruby),
+            _ => Err(()),
+        }
+    }
+}
+
+impl fmt::Display for Builtin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
@@ -0,0 +1,146 @@
+use std::{ffi::OsString, io, path::PathBuf};
+
+use super::Compiler;
+use crate::walk;
+
+/// Compiler which writes binary `FileDescriptorSet` of protos instead of
+/// generating code.
+#[derive(Debug, Clone)]
+pub struct DescriptorSetCompiler {
+    path: PathBuf,
+    include_paths: Vec<PathBuf>,
+    proto_paths: Vec<PathBuf>,
+    output: PathBuf,
+    include_imports: bool,
+    include_source_info: bool,
+    retain_options: bool,
+}
+
+impl DescriptorSetCompiler {
+    pub fn new<P, O>(path: P, output: O) -> Self
+    where
+        P: Into<PathBuf>,
+        O: Into<PathBuf>,
+    {
+        Self {
+            path: path.into(),
+            include_paths: vec![],
+            proto_paths: vec![],
+            output: output.into(),
+            include_imports: false,
+            include_source_info: false,
+            retain_options: false,
+        }
+    }
+
+    /// Sets whether imported protos should be included to make the set
+    /// self-contained.
+    pub fn set_include_imports(&mut self, include_imports: bool) {
+        self.include_imports = include_imports;
+    }
+
+    /// Sets whether source locations and comments should be kept.
+    pub fn set_include_source_info(&mut self, include_source_info: bool) {
+        self.include_source_info = include_source_info;
+    }
+
+    /// Sets whether source-retention options should be kept.
+    pub fn set_retain_options(&mut self, retain_options: bool) {
+        self.retain_options = retain_options;
+    }
+}
+
+impl Compiler for DescriptorSetCompiler {
+    fn add_include<P: Into<PathBuf>>(&mut self, path: P) -> io::Result<()> {
+        self.include_paths.push(path.into());
+        Ok(())
+    }
+
+    fn set_protos<W: walk::Walker>(&mut self, protos: W) -> io::Result<()> {
+        self.proto_paths = protos.collect::<io::Result<_>>()?;
+        Ok(())
+    }
+
+    fn command(self) -> Vec<OsString> {
+        let mut buf = Vec::with_capacity(self.include_paths.len() * 2 + self.proto_paths.len() + 5);
+
+        buf.push(self.path.into_os_string());
+        for include in self.include_paths {
+            buf.push("-I".into());
+            buf.push(include.into_os_string());
+        }
+
+        let mut output = OsString::from("--descriptor_set_out=");
+        output.push(self.output);
+        buf.push(output);
+
+        if self.include_imports {
+            buf.push("--include_imports".into());
+        }
+
+        if self.include_source_info {
+            buf.push("--include_source_info".into());
+        }
+
+        if self.retain_options {
+            buf.push("--retain_options".into());
+        }
+
+        for proto in self.proto_paths {
+            buf.push(proto.into_os_string());
+        }
+
+        buf
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn command(compiler: DescriptorSetCompiler) -> Vec<String> {
+        compiler
+            .command()
+            .into_iter()
+            .map(|a| a.into_string().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn passes_descriptor_set_flags() {
+        let mut compiler = DescriptorSetCompiler::new("protoc", "out/set.pb");
+        compiler.add_include("include").unwrap();
+        let protos = vec![PathBuf::from("a.proto"), PathBuf::from("b.proto")];
+        compiler.set_protos(protos.into_iter().map(Ok)).unwrap();
+
+        assert_eq!(
+            command(compiler.clone()),
+            [
+                "protoc",
+                "-I",
+                "include",
+                "--descriptor_set_out=out/set.pb",
+                "a.proto",
+                "b.proto"
+            ]
+        );
+
+        compiler.set_include_imports(true);
+        compiler.set_include_source_info(true);
+        compiler.set_retain_options(true);
+        assert_eq!(
+            command(compiler),
+            [
+                "protoc",
+                "-I",
+                "include",
+                "--descriptor_set_out=out/set.pb",
+                "--include_imports",
+                "--include_source_info",
+                "--retain_options",
+                "a.proto",
+                "b.proto"
+            ]
+        );
+    }
+}
@@ -3,11 +3,12 @@ use std::{
     ffi::{OsStr, OsString},
     fs, io,
     path::{Path, PathBuf},
-    process::Command,
-    rc::Rc,
     string,
+    sync::Arc,
 };
 
+use log::debug;
+
 use super::{plain::PlainCompiler, Compiler, Plugin};
 use crate::walk;
 
@@ -23,7 +24,7 @@ pub enum GoError {
 pub struct GoCompiler {
     compiler: PlainCompiler,
     import_path: String,
-    output_excludes: Option<Rc<HashSet<PathBuf>>>,
+    output_excludes: Option<Arc<HashSet<PathBuf>>>,
 
     // TODO: use protoc to get go_module option from google's protos
     compiler_includes: Option<PathBuf>,
@@ -36,8 +37,14 @@ impl GoCompiler {
         package_path.push(module);
         package_path.reverse();
 
-        let compiler = PlainCompiler::new(path.into(), plugin);
         let import_path = package_path.join("/");
+        debug!(
+            "go: import path of {:?} is {}",
+            plugin.output(),
+            import_path
+        );
+
+        let compiler = PlainCompiler::new(path.into(), plugin);
         Ok(Self {
             compiler,
             import_path,
@@ -58,14 +65,14 @@ impl GoCompiler {
         I: Iterator<Item = P>,
         P: Into<PathBuf>,
     {
-        self.output_excludes = Some(Rc::new(excludes.map(|p| p.into()).collect()));
+        self.output_excludes = Some(Arc::new(excludes.map(|p| p.into()).collect()));
     }
 
     // TODO: protoc to get 'go_module' and handle conversion errors
     fn map_packages(&mut self, path: &Path) -> io::Result<()> {
         let excludes = self
             .output_excludes
-            .get_or_insert_with(|| Rc::new(HashSet::new()));
+            .get_or_insert_with(|| Arc::new(HashSet::new()));
         let walker = walk::deep::DeepProtoWalker::new(path, excludes.clone());
 
         for proto in walker {
@@ -75,10 +82,15 @@ impl GoCompiler {
 
             relative_path = relative_path.parent().expect("not a proto");
             mapping.push_str(&self.import_path);
-            mapping.push_str("/");
-            mapping.push_str(relative_path.to_str().expect("utf-8 path expected"));
+            if !relative_path.as_os_str().is_empty() {
+                mapping.push('/');
+                mapping.push_str(relative_path.to_str().expect("utf-8 path expected"));
+            }
 
-            self.compiler.plugin_mut().add_option(mapping);
+            debug!("go: {}", mapping);
+            for plugin in self.compiler.plugins_mut() {
+                plugin.add_option(mapping.clone());
+            }
         }
 
         Ok(())
@@ -105,18 +117,38 @@ impl Compiler for GoCompiler {
     }
 }
 
+/// Reads module path from the `go.mod` the output directory belongs to.
+///
+/// Only the `module <path>` directive is parsed, so that `go` isn't needed
+/// to print commands. `go.mod` files passed with `-modfile` in `GOFLAGS` are
+/// not taken into account.
 fn derive_module(out_path: &Path) -> Result<String, GoError> {
-    let output = Command::new("go")
-        .args(&["list", "-m"])
-        .current_dir(&out_path)
-        .output()?;
-
-    let mut module = String::from_utf8(output.stdout)?;
-    if module.ends_with("\n") {
-        module.truncate(module.len() - 1);
+    for ancestor in out_path.ancestors() {
+        let go_mod = ancestor.join("go.mod");
+        if !go_mod.is_file() {
+            continue;
+        }
+
+        let content = fs::read_to_string(&go_mod)?;
+        return content
+            .lines()
+            .find_map(module_directive)
+            .ok_or_else(|| GoError::Parsing("no module directive in go.mod".to_owned()));
     }
 
-    Ok(module)
+    Err(GoError::NoModules)
+}
+
+/// Returns module path if `line` is a `module` directive.
+fn module_directive(line: &str) -> Option<String> {
+    let line = line.split("//").next().unwrap_or("").trim();
+    let path = line.strip_prefix("module")?;
+    if !path.starts_with(char::is_whitespace) {
+        return None;
+    }
+
+    let path = path.trim().trim_matches(|c| c == '"' || c == '`');
+    Some(path.to_owned()).filter(|p| !p.is_empty())
 }
 
 fn package_path(out_path: &Path) -> Result<Vec<String>, GoError> {
@@ -166,3 +198,30 @@ impl From<std::string::FromUtf8Error> for GoError {
         GoError::Parsing(e.to_string())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_module_directive() {
+        let module = module_directive;
+
+        assert_eq!(
+            module("module example.com/m"),
+            Some("example.com/m".to_owned())
+        );
+        assert_eq!(
+            module("module\t\"example.com/m\" // comment"),
+            Some("example.com/m".to_owned())
+        );
+        assert_eq!(
+            module("  module `example.com/m`"),
+            Some("example.com/m".to_owned())
+        );
+        assert_eq!(module("modulefoo example.com/m"), None);
+        assert_eq!(module("module"), None);
+        assert_eq!(module("// module example.com/m"), None);
+        assert_eq!(module("go 1.21"), None);
+    }
+}
@@ -7,7 +7,7 @@ use crate::walk;
 pub struct PlainCompiler {
     path: PathBuf,
     include_paths: Vec<PathBuf>,
-    plugin: Plugin,
+    plugins: Vec<Plugin>,
     proto_paths: Vec<PathBuf>,
 }
 
@@ -19,13 +19,18 @@ impl PlainCompiler {
         Self {
             path: path.into(),
             include_paths,
-            plugin,
+            plugins: vec![plugin],
             proto_paths,
         }
     }
 
-    pub(super) fn plugin_mut(&mut self) -> &mut Plugin {
-        &mut self.plugin
+    /// Adds another plugin to run in the same protoc invocation.
+    pub fn add_plugin(&mut self, plugin: Plugin) {
+        self.plugins.push(plugin);
+    }
+
+    pub(super) fn plugins_mut(&mut self) -> &mut [Plugin] {
+        &mut self.plugins
     }
 }
 
@@ -37,9 +42,8 @@ impl Compiler for PlainCompiler {
 
     fn set_protos<W: walk::Walker>(&mut self, protos: W) -> io::Result<()> {
         let mut buf = vec![];
-        match protos.size_hint() {
-            (x, Some(y)) => buf.reserve(y - x),
-            _ => (),
+        if let (x, Some(y)) = protos.size_hint() {
+            buf.reserve(y - x)
         }
 
         for proto in protos {
@@ -51,8 +55,7 @@ impl Compiler for PlainCompiler {
     }
 
     fn command(self) -> Vec<OsString> {
-        let mut buf = vec![];
-        buf.reserve(self.include_paths.len() * 2 + self.proto_paths.len());
+        let mut buf = Vec::with_capacity(self.include_paths.len() * 2 + self.proto_paths.len());
 
         buf.push(self.path.into_os_string());
         for include in self.include_paths {
@@ -60,7 +63,10 @@ impl Compiler for PlainCompiler {
             buf.push(include.into_os_string());
         }
 
-        buf.append(&mut self.plugin.args());
+        for plugin in self.plugins {
+            buf.append(&mut plugin.args());
+        }
+
         for proto in self.proto_paths {
             buf.push(proto.into_os_string());
         }
@@ -0,0 +1,145 @@
+use std::{fmt, path::PathBuf, sync::OnceLock};
+
+use regex::Regex;
+
+/// A single message reported by protoc on its stderr.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub file: Option<PathBuf>,
+    pub line: Option<u32>,
+    pub column: Option<u32>,
+    pub message: String,
+}
+
+/// Matches messages prefixed with a proto file and optional position.
+static LOCATION: OnceLock<Regex> = OnceLock::new();
+
+/// Parses protoc's stderr into a list of diagnostics.
+///
+/// Lines that don't reference a proto file (plugin failures, missing
+/// arguments, etc.) are kept as diagnostics without location.
+pub fn parse(stderr: &str) -> Vec<Diagnostic> {
+    let location = LOCATION.get_or_init(|| {
+        Regex::new(r#"^(.+?\.proto):(?:(\d+):(\d+):)?\s*(.*)$"#).expect("invalid regex")
+    });
+
+    let mut diagnostics = vec![];
+
+    for line in stderr.lines() {
+        let line = line.trim_end();
+        if line.is_empty() {
+            continue;
+        }
+
+        let captures = match location.captures(line) {
+            Some(captures) => captures,
+            None => {
+                diagnostics.push(Diagnostic {
+                    file: None,
+                    line: None,
+                    column: None,
+                    message: line.to_owned(),
+                });
+                continue;
+            }
+        };
+
+        diagnostics.push(Diagnostic {
+            file: captures.get(1).map(|m| PathBuf::from(m.as_str())),
+            line: captures.get(2).and_then(|m| m.as_str().parse().ok()),
+            column: captures.get(3).and_then(|m| m.as_str().parse().ok()),
+            message: captures.get(4).map_or("", |m| m.as_str()).to_owned(),
+        });
+    }
+
+    diagnostics
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(ref file) = self.file {
+            write!(f, "{}:", file.display())?;
+            if let (Some(line), Some(column)) = (self.line, self.column) {
+                write!(f, "{}:{}:", line, column)?;
+            }
+
+            write!(f, " ")?;
+        }
+
+        write!(f, "{}", self.message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn diagnostic(file: Option<&str>, position: Option<(u32, u32)>, message: &str) -> Diagnostic {
+        Diagnostic {
+            file: file.map(PathBuf::from),
+            line: position.map(|p| p.0),
+            column: position.map(|p| p.1),
+            message: message.to_owned(),
+        }
+    }
+
+    #[test]
+    fn parses_located_messages() {
+        let stderr = "a/b.proto:12:5: \"Foo\" is not defined.\n\
+                      a/c.proto: File not found.\n";
+
+        assert_eq!(
+            parse(stderr),
+            [
+                diagnostic(Some("a/b.proto"), Some((12, 5)), "\"Foo\" is not defined."),
+                diagnostic(Some("a/c.proto"), None, "File not found."),
+            ]
+        );
+    }
+
+    #[test]
+    fn parses_messages_without_location() {
+        let stderr = "--go_out: protoc-gen-go: Plugin failed with status code 1.\n\n\
+                      Missing output directives.\r\n";
+
+        assert_eq!(
+            parse(stderr),
+            [
+                diagnostic(
+                    None,
+                    None,
+                    "--go_out: protoc-gen-go: Plugin failed with status code 1."
+                ),
+                diagnostic(None, None, "Missing output directives."),
+            ]
+        );
+    }
+
+    #[test]
+    fn parses_windows_paths() {
+        let stderr = "C:\\protos\\a.proto:3:1: Expected \";\".\n\
+                      C:\\protos\\b.proto: Import \"c.proto\" was not found.\n";
+
+        assert_eq!(
+            parse(stderr),
+            [
+                diagnostic(Some("C:\\protos\\a.proto"), Some((3, 1)), "Expected \";\"."),
+                diagnostic(
+                    Some("C:\\protos\\b.proto"),
+                    None,
+                    "Import \"c.proto\" was not found."
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn displays_location() {
+        let diagnostics = parse("a.proto:1:2: bad\na.proto: worse\nplugin failed\n");
+        let displayed: Vec<_> = diagnostics.iter().map(ToString::to_string).collect();
+        assert_eq!(
+            displayed,
+            ["a.proto:1:2: bad", "a.proto: worse", "plugin failed"]
+        );
+    }
+}
@@ -1,16 +1,34 @@
+mod archive;
+mod cache;
 mod github;
+mod http;
 mod layout;
+mod local;
+mod system;
+#[cfg(test)]
+mod test_server;
+mod url;
 
 use std::{
     error::Error,
-    fmt,
+    fmt, iter,
     path::{Path, PathBuf},
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
 };
 
+use fs2::FileExt;
+use log::{debug, info};
 use semver::Version;
-use zip::ZipArchive;
+use sha2::{Digest, Sha256};
 
+pub use archive::ArchiveKind;
+pub use cache::{caches_path, CachedProtoc, ProtocCache, CACHE_DIR_ENV};
 pub use github::GithubDownloader;
+pub use http::{DownloadProgress, Retry};
+pub use local::LocalDownloader;
+pub use system::{SystemError, SystemProvider};
+pub use url::UrlDownloader;
 
 #[derive(Debug)]
 pub enum DownloadError {
@@ -18,17 +36,93 @@ pub enum DownloadError {
     Request(reqwest::Error),
     NotFound,
     Corrupted,
+    ChecksumMismatch {
+        name: String,
+        expected: String,
+        actual: String,
+    },
+    /// GitHub API rate limit is exceeded until `reset`.
+    RateLimited {
+        reset: Option<SystemTime>,
+    },
+    /// Requested release isn't among local archives, or there are none and
+    /// network access is disabled.
+    Unavailable {
+        tag: String,
+        platform: String,
+        /// Directory or archive which was searched.
+        archives: Option<PathBuf>,
+    },
+    /// Downloader can't list available releases.
+    NoReleases,
+}
+
+/// Release archive that was used to install protoc.
+#[derive(Debug, Clone)]
+pub struct Archive {
+    pub name: String,
+    pub sha256: String,
+    pub platform: String,
 }
 
 pub trait ProtocDownloader {
     fn download(&self, tag: &str, platform: &str, path: &Path) -> Result<String, DownloadError>;
+
+    /// Returns versions of available protoc releases.
+    fn releases(&self) -> Result<Vec<Version>, DownloadError> {
+        Err(DownloadError::NoReleases)
+    }
+
+    /// Sets receiver of download progress, if the downloader reports it.
+    fn set_progress(&mut self, _progress: Arc<dyn DownloadProgress>) {}
+}
+
+pub enum AnyDownloader {
+    Github(GithubDownloader),
+    Local(LocalDownloader),
+    Url(UrlDownloader),
+}
+
+impl ProtocDownloader for AnyDownloader {
+    fn download(&self, tag: &str, platform: &str, path: &Path) -> Result<String, DownloadError> {
+        match self {
+            AnyDownloader::Github(d) => d.download(tag, platform, path),
+            AnyDownloader::Local(d) => d.download(tag, platform, path),
+            AnyDownloader::Url(d) => d.download(tag, platform, path),
+        }
+    }
+
+    fn releases(&self) -> Result<Vec<Version>, DownloadError> {
+        match self {
+            AnyDownloader::Github(d) => d.releases(),
+            AnyDownloader::Local(d) => d.releases(),
+            AnyDownloader::Url(d) => d.releases(),
+        }
+    }
+
+    fn set_progress(&mut self, progress: Arc<dyn DownloadProgress>) {
+        match self {
+            AnyDownloader::Github(d) => d.set_progress(progress),
+            AnyDownloader::Local(d) => d.set_progress(progress),
+            AnyDownloader::Url(d) => d.set_progress(progress),
+        }
+    }
+}
+
+#[allow(clippy::large_enum_variant)]
+pub enum AnyProvider {
+    Download(ProtocProvider<AnyDownloader>),
+    System(SystemProvider),
 }
 
 pub struct ProtocProvider<D> {
     version: String,
+    tag: String,
+    platform: String,
     protoc_path: PathBuf,
     binary_path: PathBuf,
     include_path: PathBuf,
+    checksum: Option<String>,
     downloader: D,
 }
 
@@ -37,31 +131,32 @@ where
     D: ProtocDownloader,
 {
     pub fn new<P: Into<PathBuf>>(version: &Version, downloader: D, caches_path: P) -> Self {
+        let tag = layout::release_tag(version);
         let version = format!("v{}", version);
 
-        let mut caches_path = caches_path.into();
-        caches_path.push("protogen");
-        caches_path.push("protoc");
-        caches_path.push(&version);
+        let mut protoc_path = caches_path.into();
+        layout::push_cache_path(&mut protoc_path);
+        protoc_path.push(&version);
+        protoc_path.push(layout::target_platform());
 
-        let mut binary_path = caches_path.clone();
-        layout::push_binary_path(&mut binary_path);
-
-        let mut include_path = caches_path.clone();
-        layout::push_include_path(&mut include_path);
-
-        ProtocProvider {
+        let mut provider = ProtocProvider {
             version,
-            protoc_path: caches_path,
-            binary_path,
-            include_path,
+            tag,
+            platform: String::new(),
+            protoc_path,
+            binary_path: PathBuf::new(),
+            include_path: PathBuf::new(),
+            checksum: None,
             downloader,
-        }
+        };
+
+        provider.set_platform(layout::target_platform());
+        provider
     }
 
     pub fn binary_path(&self) -> Option<&Path> {
         let path = &self.binary_path;
-        if !path.exists() || !path.is_file() {
+        if !self.stamp_path().is_file() || !path.is_file() {
             return None;
         }
 
@@ -70,28 +165,300 @@ where
 
     pub fn include_path(&self) -> Option<&Path> {
         let path = &self.include_path;
-        if !path.exists() || !path.is_dir() {
+        if !self.stamp_path().is_file() || !path.is_dir() {
             return None;
         }
 
         Some(path.as_path())
     }
 
-    pub fn download(&self) -> Result<(), DownloadError> {
-        let platform = layout::target_platform();
+    /// Returns path of protoc binary in the cache, whether it's installed or not.
+    pub fn cached_binary_path(&self) -> &Path {
+        &self.binary_path
+    }
+
+    /// Returns path of well-known protos in the cache, whether they're installed
+    /// or not.
+    pub fn cached_include_path(&self) -> &Path {
+        &self.include_path
+    }
+
+    /// Returns name of the platform which protoc will be downloaded for.
+    pub fn platform(&self) -> &str {
+        &self.platform
+    }
+
+    /// Overrides the platform, e.g. to populate the cache for another one.
+    ///
+    /// Every platform is cached in its own directory of the version.
+    pub fn set_platform<S: Into<String>>(&mut self, platform: S) {
+        self.platform = platform.into();
+        self.protoc_path.set_file_name(&self.platform);
+
+        self.binary_path = self.protoc_path.clone();
+        layout::push_binary_path(&mut self.binary_path, &self.platform);
+
+        self.include_path = self.protoc_path.clone();
+        layout::push_include_path(&mut self.include_path);
+    }
+
+    /// Sets expected SHA-256 of the release archive.
+    ///
+    /// If set, archives with a different checksum will be refused.
+    pub fn set_checksum<S: Into<String>>(&mut self, sha256: S) {
+        self.checksum = Some(sha256.into());
+    }
+
+    /// Returns archive which the cached protoc was installed from.
+    pub fn installed_archive(&self) -> Option<Archive> {
+        let stamp = std::fs::read_to_string(self.stamp_path()).ok()?;
+        let mut lines = stamp.lines();
+        let name = lines.next()?.to_owned();
+        let sha256 = lines.next()?.to_owned();
+        let platform = lines.next()?.to_owned();
+
+        Some(Archive {
+            name,
+            sha256,
+            platform,
+        })
+    }
+
+    /// Returns whether protoc is completely installed for the platform and
+    /// matches the expected checksum.
+    pub fn is_installed(&self) -> bool {
+        match self.installed_archive() {
+            Some(archive) => {
+                self.platforms().any(|p| p == archive.platform)
+                    && self.checksum_matches(&archive.sha256)
+            }
+            None => false,
+        }
+    }
+
+    /// Downloads and installs protoc into the cache unless it's installed.
+    ///
+    /// The archive is extracted into a staging directory which then replaces
+    /// the cached version. Concurrent installations of the same version and
+    /// platform wait for each other. A partially downloaded archive is kept in
+    /// the staging directory, so that the next installation resumes it.
+    pub fn download(&self) -> Result<Archive, DownloadError> {
+        let caches_path = self.protoc_path.parent().expect("no protoc cache");
+        std::fs::create_dir_all(caches_path)?;
+
+        let lock = self.lock()?;
+        if self.is_installed() {
+            // another process has installed it while we were waiting
+            if let Some(archive) = self.installed_archive() {
+                debug!("protoc {} has been installed concurrently", self.version);
+                return Ok(archive);
+            }
+        }
+
+        info!(
+            "installing protoc {} for {} into {:?}",
+            self.version, self.platform, self.protoc_path
+        );
+
+        let staging_path = self.staging_path();
+        self.clean_dir(&staging_path)?;
+        let archive = self.install(&staging_path)?;
+
+        if self.protoc_path.exists() {
+            std::fs::remove_dir_all(&self.protoc_path)?;
+        }
+
+        std::fs::rename(&staging_path, &self.protoc_path)?;
+        lock.unlock()?;
+
+        Ok(archive)
+    }
+
+    /// Records that cached protoc has been used just now.
+    pub fn mark_used(&self) -> Result<(), std::io::Error> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+
+        let mut used_path = self.protoc_path.clone();
+        layout::push_used_path(&mut used_path);
+        std::fs::write(used_path, format!("{}\n", now))
+    }
+
+    /// Removes cached protoc, returns `false` if it's not cached.
+    ///
+    /// Waits for concurrent installations of the same version and platform to
+    /// finish.
+    pub fn remove(&self) -> Result<bool, std::io::Error> {
+        if !self.protoc_path.exists() {
+            return Ok(false);
+        }
+
+        let lock = self.lock()?;
+        let removed = self.protoc_path.exists();
+        if removed {
+            debug!("removing {:?}", self.protoc_path);
+            std::fs::remove_dir_all(&self.protoc_path)?;
+        }
+
+        let _ = std::fs::remove_dir_all(self.staging_path());
+        lock.unlock()?;
+
+        Ok(removed)
+    }
+
+    /// Removes the lock file unless protoc or its partial download is cached,
+    /// returns `false` if it's kept.
+    ///
+    /// The file is removed while it's locked, so that a process waiting for
+    /// the lock takes it again.
+    pub fn remove_lock(&self) -> Result<bool, std::io::Error> {
+        let lock = self.lock()?;
+        if self.protoc_path.exists() || self.staging_path().exists() {
+            return Ok(false);
+        }
+
+        debug!("removing {:?}", self.lock_path());
+        std::fs::remove_file(self.lock_path())?;
+        lock.unlock()?;
+
+        Ok(true)
+    }
+
+    fn install(&self, path: &Path) -> Result<Archive, DownloadError> {
+        let (platform, archive_name) = self.download_archive(path)?;
+        let archive_path = path.join(&archive_name);
+        let archive = self.install_archive(path, platform, archive_name);
+        if archive.is_err() {
+            // complete archive is downloaded anew rather than resumed
+            let _ = std::fs::remove_file(archive_path);
+        }
+
+        archive
+    }
+
+    fn install_archive(
+        &self,
+        path: &Path,
+        platform: &str,
+        archive_name: String,
+    ) -> Result<Archive, DownloadError> {
+        let sha256 = self.checksum_archive(path, &archive_name)?;
+        if !self.checksum_matches(&sha256) {
+            return Err(DownloadError::ChecksumMismatch {
+                name: archive_name,
+                expected: self.checksum.clone().unwrap_or_default(),
+                actual: sha256,
+            });
+        }
 
-        self.clean_dir(&self.protoc_path)?;
-        let zip_name = self
-            .downloader
-            .download(&self.version, platform, &self.protoc_path)?;
-        self.extract_zip(&zip_name)?;
+        let archive_path = path.join(&archive_name);
+        let kind = ArchiveKind::detect(&archive_path)?;
+        debug!("extracting {} as {:?}", archive_name, kind);
+        kind.extract(&archive_path, path)?;
 
-        match (self.binary_path(), self.include_path()) {
-            (Some(_), Some(_)) => Ok(()),
-            _ => Err(DownloadError::Corrupted),
+        let mut binary_path = path.to_owned();
+        layout::push_binary_path(&mut binary_path, &self.platform);
+        let mut include_path = path.to_owned();
+        layout::push_include_path(&mut include_path);
+        if !binary_path.is_file() || !include_path.is_dir() {
+            return Err(DownloadError::Corrupted);
         }
+
+        let mut stamp_path = path.to_owned();
+        layout::push_stamp_path(&mut stamp_path);
+        let stamp = format!("{}\n{}\n{}\n", archive_name, sha256, platform);
+        std::fs::write(stamp_path, stamp)?;
+
+        Ok(Archive {
+            name: archive_name,
+            sha256,
+            platform: platform.to_owned(),
+        })
     }
 
+    /// Downloads release archive for the first available platform.
+    fn download_archive(&self, path: &Path) -> Result<(&str, String), DownloadError> {
+        let mut error = None;
+        for platform in self.platforms() {
+            match self.downloader.download(&self.tag, platform, path) {
+                Ok(name) => return Ok((platform, name)),
+                Err(e @ DownloadError::NotFound) | Err(e @ DownloadError::Unavailable { .. }) => {
+                    debug!("no protoc {} for {}: {}", self.tag, platform, e);
+                    error.get_or_insert(e);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        Err(error.unwrap_or(DownloadError::NotFound))
+    }
+
+    fn platforms(&self) -> impl Iterator<Item = &str> {
+        let fallbacks = layout::fallback_platforms(&self.platform);
+        iter::once(self.platform.as_str()).chain(fallbacks.iter().copied())
+    }
+
+    /// Takes an exclusive lock of the cached version for the platform.
+    fn lock(&self) -> Result<std::fs::File, std::io::Error> {
+        let lock_path = self.lock_path();
+        loop {
+            let file = std::fs::OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(&lock_path);
+
+            let file = match file {
+                Ok(file) => file,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                    // the version directory has been pruned meanwhile
+                    std::fs::create_dir_all(lock_path.parent().expect("no protoc cache"))?;
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+
+            file.lock_exclusive()?;
+            if is_same_file(&file, &lock_path) {
+                return Ok(file);
+            }
+
+            // the file has been removed by `remove_lock` while we waited
+            debug!("lock {:?} has been removed, locking again", lock_path);
+        }
+    }
+
+    fn lock_path(&self) -> PathBuf {
+        let mut path = self.protoc_path.clone();
+        path.set_file_name(format!("{}.lock", self.platform));
+        path
+    }
+
+    fn stamp_path(&self) -> PathBuf {
+        let mut path = self.protoc_path.clone();
+        layout::push_stamp_path(&mut path);
+        path
+    }
+
+    fn checksum_matches(&self, sha256: &str) -> bool {
+        match self.checksum {
+            Some(ref expected) => expected.eq_ignore_ascii_case(sha256),
+            None => true,
+        }
+    }
+
+    /// Returns directory protoc is installed into before it's moved into
+    /// place. It's only used under the lock.
+    fn staging_path(&self) -> PathBuf {
+        let mut path = self.protoc_path.clone();
+        path.set_file_name(format!(".{}.tmp", self.platform));
+        path
+    }
+
+    /// Removes leftovers of a failed installation but partially downloaded
+    /// archives.
     fn clean_dir(&self, path: &Path) -> Result<(), std::io::Error> {
         if !path.exists() {
             return std::fs::create_dir_all(path);
@@ -99,60 +466,42 @@ where
 
         for content in path.read_dir()? {
             let content = content?;
+            let name = content.file_name();
             if content.file_type()?.is_dir() {
-                std::fs::remove_dir_all(&content.path())?;
-            } else {
-                std::fs::remove_file(&content.path())?;
+                std::fs::remove_dir_all(content.path())?;
+            } else if name.to_str().and_then(ArchiveKind::from_name).is_none() {
+                std::fs::remove_file(content.path())?;
             }
         }
 
         Ok(())
     }
 
-    fn extract_zip(&self, name: &str) -> Result<(), std::io::Error> {
-        let mut path = PathBuf::from(&self.protoc_path);
+    fn checksum_archive(&self, dir: &Path, name: &str) -> Result<String, std::io::Error> {
+        let mut path = PathBuf::from(dir);
         path.push(name);
 
         let mut file = std::fs::File::open(&path)?;
-        let mut archive = ZipArchive::new(&mut file)?;
-
-        for i in 0..archive.len() {
-            let mut zipfile = archive.by_index(i).unwrap();
-            let mut out_path = PathBuf::from(&self.protoc_path);
-            out_path.push(zipfile.sanitized_name());
-
-            if zipfile.name().ends_with('/') {
-                std::fs::create_dir_all(&out_path)?;
-            } else {
-                if let Some(p) = out_path.parent() {
-                    if !p.exists() {
-                        std::fs::create_dir_all(&p)?;
-                    }
-                }
+        let mut hasher = Sha256::new();
+        std::io::copy(&mut file, &mut hasher)?;
 
-                let mut outfile = std::fs::File::create(&out_path)?;
-                std::io::copy(&mut zipfile, &mut outfile)?;
-            }
+        Ok(format!("{:x}", hasher.finalize()))
+    }
+}
 
-            #[cfg(unix)]
-            self.set_permissions(&zipfile, &out_path)?;
+impl AnyProvider {
+    pub fn binary_path(&self) -> Option<&Path> {
+        match self {
+            AnyProvider::Download(p) => p.binary_path(),
+            AnyProvider::System(p) => p.binary_path(),
         }
-
-        Ok(())
     }
 
-    #[cfg(unix)]
-    fn set_permissions(&self, zipfile: &zip::read::ZipFile, path: &Path) -> std::io::Result<()> {
-        use std::{
-            fs::{set_permissions, Permissions},
-            os::unix::fs::PermissionsExt,
-        };
-
-        if let Some(mode) = zipfile.unix_mode() {
-            return set_permissions(&path, Permissions::from_mode(mode));
+    pub fn include_path(&self) -> Option<&Path> {
+        match self {
+            AnyProvider::Download(p) => p.include_path(),
+            AnyProvider::System(p) => p.include_path(),
         }
-
-        Ok(())
     }
 }
 
@@ -175,8 +524,148 @@ impl fmt::Display for DownloadError {
             DownloadError::Request(e) => e.fmt(f),
             DownloadError::NotFound => write!(f, "not found"),
             DownloadError::Corrupted => write!(f, "corrupted"),
+            DownloadError::ChecksumMismatch {
+                name,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "checksum mismatch for {}: expected {}, got {}",
+                name, expected, actual
+            ),
+            DownloadError::RateLimited { reset } => {
+                write!(f, "GitHub API rate limit exceeded")?;
+                let wait = reset.and_then(|r| r.duration_since(SystemTime::now()).ok());
+                if let Some(wait) = wait {
+                    let secs = wait.as_secs();
+                    write!(f, ", resets in {}m {}s", secs / 60, secs % 60)?;
+                }
+
+                write!(f, " (set GITHUB_TOKEN to raise the limit)")
+            }
+            DownloadError::Unavailable {
+                tag,
+                platform,
+                archives: Some(archives),
+            } => write!(
+                f,
+                "protoc {} for {} isn't found in {:?}",
+                tag, platform, archives
+            ),
+            DownloadError::Unavailable { tag, platform, .. } => {
+                write!(f, "protoc {} for {} isn't available offline", tag, platform)
+            }
+            DownloadError::NoReleases => write!(
+                f,
+                "can't list protoc releases from this source, use an exact version"
+            ),
         }
     }
 }
 
 impl Error for DownloadError {}
+
+/// Returns whether `file` is still linked at `path`.
+#[cfg(unix)]
+fn is_same_file(file: &std::fs::File, path: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+
+    match (file.metadata(), std::fs::metadata(path)) {
+        (Ok(a), Ok(b)) => a.dev() == b.dev() && a.ino() == b.ino(),
+        _ => false,
+    }
+}
+
+/// Returns whether `file` is still linked at `path`.
+///
+/// Files can't be removed while they're open on Windows.
+#[cfg(not(unix))]
+fn is_same_file(_file: &std::fs::File, path: &Path) -> bool {
+    path.exists()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs::File, io::Write};
+
+    use zip::{write::FileOptions, ZipWriter};
+
+    use super::*;
+    use crate::temp::TempDir;
+
+    /// Writes a release archive of protoc for `platform` into `dir`.
+    fn write_release(dir: &Path, version: &Version, platform: &str) {
+        let tag = layout::release_tag(version);
+        let path = dir.join(layout::archive_name(&tag, platform));
+        let mut zip = ZipWriter::new(File::create(path).unwrap());
+
+        let mut binary_path = PathBuf::new();
+        layout::push_binary_path(&mut binary_path, platform);
+        let binary_name = binary_path.to_str().unwrap().replace('\\', "/");
+        zip.start_file(binary_name, FileOptions::default()).unwrap();
+        zip.write_all(b"protoc").unwrap();
+
+        let options = FileOptions::default();
+        zip.start_file("include/google/protobuf/empty.proto", options)
+            .unwrap();
+        zip.write_all(b"syntax = \"proto3\";\n").unwrap();
+        zip.finish().unwrap();
+    }
+
+    fn make_provider(dir: &Path) -> ProtocProvider<LocalDownloader> {
+        let version = Version::new(3, 12, 3);
+        let archives = dir.join("archives");
+        std::fs::create_dir_all(&archives).unwrap();
+        write_release(&archives, &version, layout::target_platform());
+
+        let downloader = LocalDownloader::new(archives);
+        ProtocProvider::new(&version, downloader, dir.join("cache"))
+    }
+
+    #[test]
+    fn installs_local_archive() {
+        let dir = TempDir::new("protogen-test-provider-install").unwrap();
+        let provider = make_provider(dir.path());
+
+        let archive = provider.download().unwrap();
+        assert_eq!(archive.platform, layout::target_platform());
+        assert!(provider.is_installed());
+        assert_eq!(
+            std::fs::read(provider.binary_path().unwrap()).unwrap(),
+            b"protoc"
+        );
+        assert!(provider.include_path().is_some());
+
+        let installed = provider.installed_archive().unwrap();
+        assert_eq!(installed.name, archive.name);
+        assert_eq!(installed.sha256, archive.sha256);
+    }
+
+    #[test]
+    fn refuses_archive_with_checksum_mismatch() {
+        let dir = TempDir::new("protogen-test-provider-checksum").unwrap();
+        let mut provider = make_provider(dir.path());
+        provider.set_checksum("0".repeat(64));
+
+        match provider.download() {
+            Err(DownloadError::ChecksumMismatch { name, expected, .. }) => {
+                assert_eq!(
+                    name,
+                    layout::archive_name(&provider.tag, &provider.platform)
+                );
+                assert_eq!(expected, "0".repeat(64));
+            }
+            other => panic!("unexpected result: {:?}", other.map(|a| a.name)),
+        }
+
+        assert!(!provider.is_installed());
+        assert!(provider.binary_path().is_none());
+        assert!(!provider.protoc_path.exists());
+
+        // the refused archive isn't resumed by the next installation
+        let staged: Vec<_> = std::fs::read_dir(provider.staging_path())
+            .unwrap()
+            .collect();
+        assert!(staged.is_empty());
+    }
+}
@@ -0,0 +1,231 @@
+use std::{
+    fs::{self, File},
+    io::{self, Read},
+    path::{Component, Path},
+};
+
+use flate2::read::GzDecoder;
+use tar::EntryType;
+use xz2::read::XzDecoder;
+use zip::ZipArchive;
+
+/// Format of a protoc release archive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArchiveKind {
+    Zip,
+    TarGz,
+    TarXz,
+}
+
+/// File name extensions of supported archives.
+pub const EXTENSIONS: [&str; 5] = ["zip", "tar.gz", "tgz", "tar.xz", "txz"];
+
+impl ArchiveKind {
+    /// Detects archive format by its file name or, if the name is not
+    /// conclusive, by its first bytes.
+    pub fn detect(path: &Path) -> io::Result<Self> {
+        let name = path.file_name().and_then(|n| n.to_str()).unwrap_or("");
+        if let Some(kind) = Self::from_name(name) {
+            return Ok(kind);
+        }
+
+        let mut magic = [0; 6];
+        let mut file = File::open(path)?;
+        let read = file.read(&mut magic)?;
+
+        match &magic[..read] {
+            [0x50, 0x4b, 0x03, 0x04, ..] => Ok(ArchiveKind::Zip),
+            [0x1f, 0x8b, ..] => Ok(ArchiveKind::TarGz),
+            [0xfd, b'7', b'z', b'X', b'Z', 0x00] => Ok(ArchiveKind::TarXz),
+            _ => Err(invalid_data("unsupported archive format")),
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        if name.ends_with(".zip") {
+            Some(ArchiveKind::Zip)
+        } else if name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+            Some(ArchiveKind::TarGz)
+        } else if name.ends_with(".tar.xz") || name.ends_with(".txz") {
+            Some(ArchiveKind::TarXz)
+        } else {
+            None
+        }
+    }
+
+    /// Extracts archive at `path` into `dir`.
+    ///
+    /// Entries with absolute paths or `..` components are refused.
+    pub fn extract(self, path: &Path, dir: &Path) -> io::Result<()> {
+        let file = File::open(path)?;
+        match self {
+            ArchiveKind::Zip => extract_zip(file, dir),
+            ArchiveKind::TarGz => extract_tar(GzDecoder::new(file), dir),
+            ArchiveKind::TarXz => extract_tar(XzDecoder::new(file), dir),
+        }
+    }
+}
+
+fn extract_zip(mut file: File, dir: &Path) -> io::Result<()> {
+    let mut archive = ZipArchive::new(&mut file)?;
+
+    for i in 0..archive.len() {
+        let mut zipfile = archive.by_index(i)?;
+        let out_path = match zipfile.enclosed_name() {
+            Some(name) => dir.join(name),
+            None => return Err(invalid_data("unsafe path in archive")),
+        };
+
+        if zipfile.name().ends_with('/') {
+            fs::create_dir_all(&out_path)?;
+        } else {
+            create_parent(&out_path)?;
+            let mut outfile = File::create(&out_path)?;
+            io::copy(&mut zipfile, &mut outfile)?;
+        }
+
+        #[cfg(unix)]
+        set_permissions(&out_path, zipfile.unix_mode())?;
+    }
+
+    Ok(())
+}
+
+fn extract_tar<R: Read>(reader: R, dir: &Path) -> io::Result<()> {
+    let mut archive = tar::Archive::new(reader);
+    fs::create_dir_all(dir)?;
+
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+
+        match entry.header().entry_type() {
+            EntryType::Symlink | EntryType::Link => {
+                // links must not point outside of the archive either
+                if let Some(target) = entry.link_name()? {
+                    enclosed_path(&target)?;
+                }
+            }
+            EntryType::Regular | EntryType::Directory => (),
+            _ => continue,
+        }
+
+        // hard links are resolved relative to `dir` as well
+        if !entry.unpack_in(dir)? {
+            return Err(invalid_data("unsafe path in archive"));
+        }
+    }
+
+    Ok(())
+}
+
+/// Fails if `path` is absolute or escapes its root.
+fn enclosed_path(path: &Path) -> io::Result<()> {
+    for component in path.components() {
+        match component {
+            Component::Normal(_) | Component::CurDir => (),
+            Component::ParentDir | Component::RootDir | Component::Prefix(_) => {
+                return Err(invalid_data("unsafe path in archive"));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn create_parent(path: &Path) -> io::Result<()> {
+    match path.parent() {
+        Some(p) if !p.exists() => fs::create_dir_all(p),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(unix)]
+fn set_permissions(path: &Path, mode: Option<u32>) -> io::Result<()> {
+    use std::{fs::Permissions, os::unix::fs::PermissionsExt};
+
+    if let Some(mode) = mode {
+        return fs::set_permissions(path, Permissions::from_mode(mode));
+    }
+
+    Ok(())
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use flate2::{write::GzEncoder, Compression};
+    use tar::{Builder, Header};
+
+    use super::*;
+    use crate::temp::TempDir;
+
+    fn header(kind: EntryType, size: u64) -> Header {
+        let mut header = Header::new_gnu();
+        header.set_entry_type(kind);
+        header.set_size(size);
+        header.set_mode(0o755);
+        header
+    }
+
+    fn write_tar_gz(path: &Path, build: impl FnOnce(&mut Builder<GzEncoder<File>>)) {
+        let encoder = GzEncoder::new(File::create(path).unwrap(), Compression::fast());
+        let mut builder = Builder::new(encoder);
+        build(&mut builder);
+        builder.into_inner().unwrap().finish().unwrap();
+    }
+
+    #[test]
+    fn extracts_tar_with_hard_link() {
+        let dir = TempDir::new("protogen-test-archive-link").unwrap();
+        let path = dir.path().join("protoc.tar.gz");
+        write_tar_gz(&path, |builder| {
+            let mut binary = header(EntryType::Regular, 6);
+            builder
+                .append_data(&mut binary, "bin/protoc", &b"protoc"[..])
+                .unwrap();
+
+            let mut link = header(EntryType::Link, 0);
+            builder
+                .append_link(&mut link, "bin/protoc-link", "bin/protoc")
+                .unwrap();
+        });
+
+        let out = dir.path().join("out");
+        ArchiveKind::TarGz.extract(&path, &out).unwrap();
+
+        assert_eq!(fs::read(out.join("bin/protoc")).unwrap(), b"protoc");
+        assert_eq!(fs::read(out.join("bin/protoc-link")).unwrap(), b"protoc");
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+
+            let mode = fs::metadata(out.join("bin/protoc"))
+                .unwrap()
+                .permissions()
+                .mode();
+            assert_eq!(mode & 0o111, 0o111);
+        }
+    }
+
+    #[test]
+    fn refuses_tar_escaping_dir() {
+        let dir = TempDir::new("protogen-test-archive-escape").unwrap();
+        let path = dir.path().join("protoc.tar.gz");
+        write_tar_gz(&path, |builder| {
+            let mut link = header(EntryType::Symlink, 0);
+            builder
+                .append_link(&mut link, "bin/protoc", "../../protoc")
+                .unwrap();
+        });
+
+        let out = dir.path().join("out");
+        let error = ArchiveKind::TarGz.extract(&path, &out).unwrap_err();
+
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert!(!out.join("bin/protoc").exists());
+    }
+}
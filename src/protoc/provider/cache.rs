@@ -0,0 +1,356 @@
+use std::{
+    env, fs, io,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use directories::BaseDirs;
+use semver::Version;
+
+use super::{layout, LocalDownloader, ProtocProvider};
+
+/// Environment variable to override location of the cache.
+pub const CACHE_DIR_ENV: &str = "PROTOGEN_CACHE_DIR";
+
+/// Returns directory to cache protoc releases in.
+///
+/// It's `PROTOGEN_CACHE_DIR` if set or user's cache directory otherwise.
+pub fn caches_path() -> Option<PathBuf> {
+    match env::var_os(CACHE_DIR_ENV) {
+        Some(path) if !path.is_empty() => Some(PathBuf::from(path)),
+        _ => BaseDirs::new().map(|d| d.cache_dir().to_owned()),
+    }
+}
+
+/// Protoc releases installed in the cache.
+#[derive(Debug, Clone)]
+pub struct ProtocCache {
+    caches_path: PathBuf,
+}
+
+#[derive(Debug, Clone)]
+pub struct CachedProtoc {
+    pub version: Version,
+    /// Platform of the installation, `None` if it was installed by an older
+    /// protogen directly into the version directory.
+    pub platform: Option<String>,
+    pub path: PathBuf,
+    /// Size of the installation in bytes.
+    pub size: u64,
+    pub last_used: Option<SystemTime>,
+    /// Whether the installation has been completed.
+    pub installed: bool,
+}
+
+impl ProtocCache {
+    pub fn new<P: Into<PathBuf>>(caches_path: P) -> Self {
+        Self {
+            caches_path: caches_path.into(),
+        }
+    }
+
+    pub fn caches_path(&self) -> &Path {
+        &self.caches_path
+    }
+
+    /// Returns cached protoc releases ordered by version and platform.
+    pub fn list(&self) -> io::Result<Vec<CachedProtoc>> {
+        let mut cached = vec![];
+        for (version, path) in self.versions()? {
+            let legacy = legacy_entries(&path)?;
+            if !legacy.is_empty() {
+                let mut size = 0;
+                for entry in &legacy {
+                    size += dir_size(entry)?;
+                }
+
+                cached.push(CachedProtoc {
+                    version: version.clone(),
+                    platform: None,
+                    size,
+                    last_used: last_used(&path),
+                    installed: is_installed(&path),
+                    path: path.clone(),
+                });
+            }
+
+            for platform in platforms(&path)? {
+                let path = path.join(&platform);
+                cached.push(CachedProtoc {
+                    version: version.clone(),
+                    platform: Some(platform),
+                    size: dir_size(&path)?,
+                    last_used: last_used(&path),
+                    installed: is_installed(&path),
+                    path,
+                });
+            }
+        }
+
+        cached.sort_by(|a, b| (&a.version, &a.platform).cmp(&(&b.version, &b.platform)));
+        Ok(cached)
+    }
+
+    /// Removes cached `version` for all platforms, returns `false` if it's not
+    /// cached.
+    pub fn remove(&self, version: &Version) -> io::Result<bool> {
+        let mut removed = false;
+        for protoc in self.list()? {
+            if protoc.version == *version {
+                removed |= self.remove_protoc(&protoc)?;
+            }
+        }
+
+        Ok(removed)
+    }
+
+    /// Removes all but `keep` most recently used releases.
+    ///
+    /// Returns removed releases.
+    pub fn prune(&self, keep: usize) -> io::Result<Vec<CachedProtoc>> {
+        let mut cached = self.list()?;
+
+        // incomplete installations go first, then the least recently used
+        cached.sort_by_key(|c| (c.installed, c.last_used));
+        let count = cached.len().saturating_sub(keep);
+        cached.truncate(count);
+
+        for protoc in &cached {
+            self.remove_protoc(protoc)?;
+        }
+
+        self.remove_locks()?;
+        Ok(cached)
+    }
+
+    /// Returns directories of cached versions.
+    fn versions(&self) -> io::Result<Vec<(Version, PathBuf)>> {
+        let mut path = self.caches_path.clone();
+        layout::push_cache_path(&mut path);
+        if !path.is_dir() {
+            return Ok(vec![]);
+        }
+
+        let mut versions = vec![];
+        for entry in fs::read_dir(&path)? {
+            let entry = entry?;
+            if !entry.file_type()?.is_dir() {
+                continue;
+            }
+
+            let name = entry.file_name();
+            let version = match name.to_str().and_then(|n| n.strip_prefix('v')) {
+                Some(version) => version,
+                None => continue,
+            };
+
+            if let Ok(version) = Version::parse(version) {
+                versions.push((version, entry.path()));
+            }
+        }
+
+        Ok(versions)
+    }
+
+    /// Removes lock files of platforms which aren't cached, and then empty
+    /// version directories.
+    ///
+    /// Lock files are kept by `remove`, because a concurrent installation may
+    /// wait for the lock.
+    fn remove_locks(&self) -> io::Result<()> {
+        for (version, path) in self.versions()? {
+            for entry in fs::read_dir(&path)? {
+                let name = entry?.file_name();
+                let platform = match name.to_str().and_then(|n| n.strip_suffix(".lock")) {
+                    Some(platform) => platform,
+                    None => continue,
+                };
+
+                self.provider(&version, platform).remove_lock()?;
+            }
+
+            // fails unless it's empty
+            let _ = fs::remove_dir(&path);
+        }
+
+        Ok(())
+    }
+
+    fn provider(&self, version: &Version, platform: &str) -> ProtocProvider<LocalDownloader> {
+        let mut provider =
+            ProtocProvider::new(version, LocalDownloader::default(), &self.caches_path);
+        provider.set_platform(platform);
+        provider
+    }
+
+    fn remove_protoc(&self, protoc: &CachedProtoc) -> io::Result<bool> {
+        let platform = match &protoc.platform {
+            Some(platform) => platform,
+            None => {
+                // older protogen didn't lock installations
+                for entry in legacy_entries(&protoc.path)? {
+                    match entry.is_dir() {
+                        true => fs::remove_dir_all(entry)?,
+                        false => fs::remove_file(entry)?,
+                    }
+                }
+
+                return Ok(true);
+            }
+        };
+
+        self.provider(&protoc.version, platform).remove()
+    }
+}
+
+/// Returns platforms installed into the version directory at `path`.
+fn platforms(path: &Path) -> io::Result<Vec<String>> {
+    let mut platforms = vec![];
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        if !entry.file_type()?.is_dir() {
+            continue;
+        }
+
+        match entry.file_name().into_string() {
+            Ok(name) if !name.starts_with('.') && !is_legacy_dir(&name) => platforms.push(name),
+            _ => continue,
+        }
+    }
+
+    Ok(platforms)
+}
+
+/// Returns files of an installation made by an older protogen directly into
+/// the version directory at `path`.
+fn legacy_entries(path: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut entries = vec![];
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let name = name.to_string_lossy();
+        let legacy = match entry.file_type()?.is_dir() {
+            true => is_legacy_dir(&name),
+            // lock files belong to installations of platforms
+            false => !name.ends_with(".lock"),
+        };
+
+        if legacy {
+            entries.push(entry.path());
+        }
+    }
+
+    Ok(entries)
+}
+
+fn is_legacy_dir(name: &str) -> bool {
+    name == "bin" || name == "include"
+}
+
+fn is_installed(path: &Path) -> bool {
+    let mut stamp_path = path.to_owned();
+    layout::push_stamp_path(&mut stamp_path);
+    stamp_path.is_file()
+}
+
+fn dir_size(path: &Path) -> io::Result<u64> {
+    if !path.is_dir() {
+        return fs::metadata(path).map(|m| m.len());
+    }
+
+    let mut size = 0;
+    let mut dirs = vec![path.to_owned()];
+
+    while let Some(dir) = dirs.pop() {
+        for entry in fs::read_dir(&dir)? {
+            let entry = entry?;
+            let file_type = entry.file_type()?;
+            if file_type.is_dir() {
+                dirs.push(entry.path());
+            } else if file_type.is_file() {
+                size += entry.metadata()?.len();
+            }
+        }
+    }
+
+    Ok(size)
+}
+
+fn last_used(path: &Path) -> Option<SystemTime> {
+    let mut used_path = path.to_owned();
+    layout::push_used_path(&mut used_path);
+
+    if let Ok(content) = fs::read_to_string(&used_path) {
+        if let Ok(secs) = content.trim().parse() {
+            return Some(UNIX_EPOCH + Duration::from_secs(secs));
+        }
+    }
+
+    // installed before usage was tracked
+    let mut stamp_path = path.to_owned();
+    layout::push_stamp_path(&mut stamp_path);
+    fs::metadata(stamp_path).and_then(|m| m.modified()).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::temp::TempDir;
+
+    fn install(path: &Path) {
+        let mut binary_path = path.to_owned();
+        layout::push_binary_path(&mut binary_path, "linux-x86_64");
+        fs::create_dir_all(binary_path.parent().unwrap()).unwrap();
+        fs::write(binary_path, "protoc").unwrap();
+
+        let mut stamp_path = path.to_owned();
+        layout::push_stamp_path(&mut stamp_path);
+        fs::write(stamp_path, "").unwrap();
+    }
+
+    #[test]
+    fn lists_platforms_and_legacy_installations() {
+        let dir = TempDir::new("protogen-test-cache-list").unwrap();
+        let mut path = dir.path().to_owned();
+        layout::push_cache_path(&mut path);
+
+        install(&path.join("v3.11.0"));
+        install(&path.join("v3.12.3").join("linux-x86_64"));
+        install(&path.join("v3.12.3").join("linux-aarch_64"));
+        fs::write(path.join("v3.12.3").join("linux-x86_64.lock"), "").unwrap();
+        fs::create_dir_all(path.join("v3.12.3").join(".win64.tmp")).unwrap();
+        fs::write(path.join("v3.12.3").join("win64.lock"), "").unwrap();
+
+        let cache = ProtocCache::new(dir.path());
+        let listed = |cache: &ProtocCache| -> Vec<(String, Option<String>)> {
+            let cached = cache.list().unwrap();
+            cached
+                .into_iter()
+                .map(|c| (c.version.to_string(), c.platform))
+                .collect()
+        };
+
+        assert_eq!(
+            listed(&cache),
+            vec![
+                ("3.11.0".to_owned(), None),
+                ("3.12.3".to_owned(), Some("linux-aarch_64".to_owned())),
+                ("3.12.3".to_owned(), Some("linux-x86_64".to_owned())),
+            ]
+        );
+
+        assert!(cache.remove(&Version::new(3, 11, 0)).unwrap());
+        assert!(cache.remove(&Version::new(3, 12, 3)).unwrap());
+        assert!(!cache.remove(&Version::new(3, 12, 3)).unwrap());
+        assert_eq!(listed(&cache), vec![]);
+
+        // concurrent installations may wait for the lock
+        assert!(path.join("v3.12.3").join("linux-x86_64.lock").is_file());
+
+        // partial downloads are kept for the next installation to resume
+        assert!(cache.prune(1).unwrap().is_empty());
+        assert!(!path.join("v3.11.0").exists());
+        assert!(!path.join("v3.12.3").join("linux-x86_64.lock").exists());
+        assert!(path.join("v3.12.3").join("win64.lock").is_file());
+    }
+}
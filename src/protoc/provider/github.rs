@@ -1,15 +1,50 @@
-use std::path::{Path, PathBuf};
+use std::{
+    env,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, UNIX_EPOCH},
+};
 
+use log::debug;
 use regex::Regex;
-use reqwest::blocking::Client;
+use reqwest::{
+    blocking::{Client, Response},
+    header::ACCEPT,
+    StatusCode, Url,
+};
+use semver::Version;
 use serde::Deserialize;
 
-use super::{DownloadError, ProtocDownloader};
-use std::time::Duration;
+use super::{
+    http::{self, DownloadProgress, Retry},
+    layout, DownloadError, ProtocDownloader,
+};
+
+pub const DEFAULT_API_URL: &str = "https://api.github.com";
+pub const DEFAULT_REPOSITORY: &str = "protocolbuffers/protobuf";
+
+/// Environment variables to read token for `DEFAULT_API_URL` from, in order
+/// of priority.
+const TOKEN_ENV: [&str; 2] = ["GITHUB_TOKEN", "GH_TOKEN"];
+
+/// Environment variable to read token for any API URL from, e.g. for GitHub
+/// Enterprise.
+pub const API_TOKEN_ENV: &str = "PROTOGEN_GITHUB_TOKEN";
+
+/// Number of releases per page of the releases list.
+const PAGE_SIZE: usize = 100;
+
+/// Maximum number of pages of the releases list to fetch.
+const MAX_PAGES: usize = 10;
 
 pub struct GithubDownloader {
     client: Client,
     name_regex: Regex,
+    api_url: String,
+    repository: String,
+    token: Option<String>,
+    retry: Retry,
+    progress: Option<Arc<dyn DownloadProgress>>,
 }
 
 #[derive(Deserialize)]
@@ -17,6 +52,13 @@ struct Release {
     assets: Vec<Asset>,
 }
 
+#[derive(Deserialize)]
+struct ReleaseTag {
+    tag_name: String,
+    #[serde(default)]
+    draft: bool,
+}
+
 #[derive(Deserialize)]
 struct Asset {
     name: String,
@@ -25,19 +67,107 @@ struct Asset {
 
 impl GithubDownloader {
     pub fn new(client: Client) -> Self {
-        let name_regex = Regex::new(r#"protoc-.+-((linux|osx|win).+).zip"#).unwrap();
-        Self { client, name_regex }
+        let name_regex = Regex::new(
+            r#"^protoc-.+?-((?:linux|osx|win)[A-Za-z0-9_-]*)\.(?:zip|tar\.gz|tgz|tar\.xz|txz)$"#,
+        )
+        .unwrap();
+        Self {
+            client,
+            name_regex,
+            api_url: DEFAULT_API_URL.to_owned(),
+            repository: DEFAULT_REPOSITORY.to_owned(),
+            token: None,
+            retry: Retry::default(),
+            progress: None,
+        }
+    }
+
+    /// Reads token for the API URL from `PROTOGEN_GITHUB_TOKEN` or, if it's
+    /// api.github.com, from `GITHUB_TOKEN` or `GH_TOKEN`.
+    pub fn token_from_env(&self) -> Option<String> {
+        let var = |name: &str| env::var(name).ok().filter(|t| !t.is_empty());
+        select_token(&self.api_url, var)
+    }
+
+    /// Sets base URL of GitHub API, e.g. `https://github.example.com/api/v3`
+    /// for GitHub Enterprise.
+    pub fn set_api_url<S: Into<String>>(&mut self, url: S) {
+        let mut url = url.into();
+        while url.ends_with('/') {
+            url.pop();
+        }
+
+        self.api_url = url;
+    }
+
+    /// Sets repository with protoc releases in `owner/name` format.
+    pub fn set_repository<S: Into<String>>(&mut self, repository: S) {
+        self.repository = repository.into();
+    }
+
+    /// Sets token to authenticate API requests with to avoid rate limiting.
+    pub fn set_token<S: Into<String>>(&mut self, token: S) {
+        self.token = Some(token.into());
+    }
+
+    pub fn set_retry(&mut self, retry: Retry) {
+        self.retry = retry;
+    }
+
+    fn fetch_release(&self, tag: &str) -> Result<Release, DownloadError> {
+        let url = format!(
+            "{}/repos/{}/releases/tags/{}",
+            self.api_url, self.repository, tag
+        );
+
+        let release = self.get(&url)?.json::<Release>()?;
+        Ok(release)
+    }
+
+    fn fetch_release_tags(&self, page: usize) -> Result<Vec<ReleaseTag>, DownloadError> {
+        let url = format!(
+            "{}/repos/{}/releases?per_page={}&page={}",
+            self.api_url, self.repository, PAGE_SIZE, page
+        );
+
+        let tags = self.get(&url)?.json::<Vec<ReleaseTag>>()?;
+        Ok(tags)
+    }
+
+    fn get(&self, url: &str) -> Result<Response, DownloadError> {
+        debug!("fetching {}", url);
+        let mut request = self
+            .client
+            .get(url)
+            .header(ACCEPT, "application/vnd.github.v3+json");
+        if let Some(ref token) = self.token {
+            request = request.bearer_auth(token);
+        }
+
+        let response = request.send()?;
+        if let Some(e) = rate_limit_error(&response) {
+            return Err(e);
+        }
+
+        if response.status() == StatusCode::NOT_FOUND {
+            return Err(DownloadError::NotFound);
+        }
+
+        Ok(response.error_for_status()?)
     }
 
     fn download_asset(&self, asset: Asset, path: &Path) -> Result<String, DownloadError> {
         let mut path = PathBuf::from(path);
         path.push(&asset.name);
 
-        let mut zip = std::fs::File::create(&path)?;
-        self.client
-            .get(&asset.browser_download_url)
-            .send()?
-            .copy_to(&mut zip)?;
+        http::fetch(
+            &self.client,
+            &asset.browser_download_url,
+            &asset.name,
+            &path,
+            self.retry,
+            self.progress.as_ref(),
+        )?;
 
         Ok(asset.name)
     }
@@ -45,11 +175,7 @@ impl GithubDownloader {
 
 impl ProtocDownloader for GithubDownloader {
     fn download(&self, tag: &str, platform: &str, path: &Path) -> Result<String, DownloadError> {
-        let url = format!(
-            "https://api.github.com/repos/protocolbuffers/protobuf/releases/tags/{}",
-            tag
-        );
-        let release = self.client.get(&url).send()?.json::<Release>()?;
+        let release = self.fetch_release(tag)?;
 
         for asset in release.assets {
             let captures = match self.name_regex.captures(&asset.name) {
@@ -69,11 +195,32 @@ impl ProtocDownloader for GithubDownloader {
 
         Err(DownloadError::NotFound)
     }
+
+    fn releases(&self) -> Result<Vec<Version>, DownloadError> {
+        let mut versions = vec![];
+        for page in 1..=MAX_PAGES {
+            let tags = self.fetch_release_tags(page)?;
+            let last = tags.len() < PAGE_SIZE;
+
+            let tags = tags.into_iter().filter(|t| !t.draft);
+            versions.extend(tags.filter_map(|t| layout::tag_version(&t.tag_name)));
+            if last {
+                break;
+            }
+        }
+
+        Ok(versions)
+    }
+
+    fn set_progress(&mut self, progress: Arc<dyn DownloadProgress>) {
+        self.progress = Some(progress);
+    }
 }
 
 impl Default for GithubDownloader {
     fn default() -> Self {
         let client = Client::builder()
+            .user_agent(concat!("protogen/", env!("CARGO_PKG_VERSION")))
             .gzip(true)
             .connect_timeout(Duration::from_secs(30))
             .build()
@@ -82,3 +229,121 @@ impl Default for GithubDownloader {
         GithubDownloader::new(client)
     }
 }
+
+/// Returns token to send to `api_url`, GitHub tokens aren't sent to other
+/// hosts.
+fn select_token<F>(api_url: &str, var: F) -> Option<String>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    if let Some(token) = var(API_TOKEN_ENV) {
+        return Some(token);
+    }
+
+    let url = Url::parse(api_url).ok()?;
+    if url.scheme() != "https" || url.host_str() != Some("api.github.com") {
+        return None;
+    }
+
+    TOKEN_ENV.iter().find_map(|name| var(name))
+}
+
+/// Returns an error if the response indicates exceeded API rate limit.
+fn rate_limit_error(response: &Response) -> Option<DownloadError> {
+    match response.status() {
+        StatusCode::FORBIDDEN | StatusCode::TOO_MANY_REQUESTS => (),
+        _ => return None,
+    }
+
+    let headers = response.headers();
+    let remaining = headers
+        .get("x-ratelimit-remaining")
+        .and_then(|v| v.to_str().ok());
+    if remaining != Some("0") {
+        return None;
+    }
+
+    let reset = headers
+        .get("x-ratelimit-reset")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse().ok())
+        .map(|secs| UNIX_EPOCH + Duration::from_secs(secs));
+
+    Some(DownloadError::RateLimited { reset })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::protoc::provider::test_server::{self, Response, TestServer};
+
+    #[test]
+    fn sends_github_token_to_github_only() {
+        let mut env = HashMap::new();
+        env.insert("GH_TOKEN", "gh");
+        let var = |name: &str| env.get(name).map(|t| t.to_string());
+
+        assert_eq!(select_token(DEFAULT_API_URL, var), Some("gh".to_owned()));
+        assert_eq!(
+            select_token("https://api.github.com/", var),
+            Some("gh".to_owned())
+        );
+        assert_eq!(select_token("http://api.github.com", var), None);
+        assert_eq!(select_token("https://mirror.example.com", var), None);
+        assert_eq!(
+            select_token("https://api.github.com.example.com", var),
+            None
+        );
+
+        env.insert(API_TOKEN_ENV, "enterprise");
+        let var = |name: &str| env.get(name).map(|t| t.to_string());
+        assert_eq!(
+            select_token("https://github.example.com/api/v3", var),
+            Some("enterprise".to_owned())
+        );
+    }
+
+    #[test]
+    fn reports_rate_limiting() {
+        let server = TestServer::start(vec![Response::new(403, b"rate limited")
+            .header("X-RateLimit-Remaining", "0")
+            .header("X-RateLimit-Reset", "1700000000")]);
+
+        let mut downloader = GithubDownloader::new(test_server::client());
+        downloader.set_api_url(server.url("/"));
+        downloader.set_token("secret");
+
+        match downloader.releases() {
+            Err(DownloadError::RateLimited { reset }) => {
+                assert_eq!(reset, Some(UNIX_EPOCH + Duration::from_secs(1_700_000_000)));
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+
+        let requests = server.finish();
+        assert_eq!(
+            requests[0].path,
+            "/repos/protocolbuffers/protobuf/releases?per_page=100&page=1"
+        );
+        assert_eq!(requests[0].header("authorization"), Some("Bearer secret"));
+    }
+
+    #[test]
+    fn treats_forbidden_with_remaining_limit_as_failure() {
+        let server = TestServer::start(vec![
+            Response::new(403, b"forbidden").header("X-RateLimit-Remaining", "42")
+        ]);
+
+        let mut downloader = GithubDownloader::new(test_server::client());
+        downloader.set_api_url(server.url(""));
+
+        match downloader.releases() {
+            Err(DownloadError::Request(e)) => assert_eq!(e.status(), Some(StatusCode::FORBIDDEN)),
+            other => panic!("unexpected result: {:?}", other),
+        }
+
+        server.finish();
+    }
+}
@@ -0,0 +1,286 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Read, Write},
+    path::Path,
+    sync::Arc,
+    thread,
+    time::Duration,
+};
+
+use log::{info, warn};
+use reqwest::{blocking::Client, header::RANGE, StatusCode};
+
+use super::DownloadError;
+
+/// Receives progress of protoc archive downloads.
+pub trait DownloadProgress: Send + Sync {
+    /// Called when download of `name` starts or restarts after a failure.
+    fn start(&self, name: &str, total: Option<u64>);
+    fn update(&self, downloaded: u64);
+    fn finish(&self);
+}
+
+/// Controls how failed downloads are retried.
+#[derive(Debug, Clone, Copy)]
+pub struct Retry {
+    /// Number of attempts after the first one.
+    pub retries: u32,
+    /// Delay before the first retry, doubled for every next one.
+    pub backoff: Duration,
+}
+
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+enum Failure {
+    Transient(DownloadError),
+    Fatal(DownloadError),
+}
+
+/// Downloads `url` into `path`, retrying on network and server errors.
+///
+/// Partially downloaded file is resumed with HTTP range requests if the
+/// server supports them.
+pub(super) fn fetch(
+    client: &Client,
+    url: &str,
+    name: &str,
+    path: &Path,
+    retry: Retry,
+    progress: Option<&Arc<dyn DownloadProgress>>,
+) -> Result<(), DownloadError> {
+    info!("downloading {}", url);
+
+    let mut attempt = 0;
+    loop {
+        match fetch_once(client, url, name, path, progress) {
+            Ok(()) => return Ok(()),
+            Err(Failure::Transient(e)) if attempt < retry.retries => {
+                let delay = (retry.backoff * 2u32.saturating_pow(attempt)).min(MAX_BACKOFF);
+                warn!(
+                    "download of {} failed, retrying in {:?}: {}",
+                    name, delay, e
+                );
+                thread::sleep(delay);
+                attempt += 1;
+            }
+            Err(Failure::Transient(e)) | Err(Failure::Fatal(e)) => return Err(e),
+        }
+    }
+}
+
+fn fetch_once(
+    client: &Client,
+    url: &str,
+    name: &str,
+    path: &Path,
+    progress: Option<&Arc<dyn DownloadProgress>>,
+) -> Result<(), Failure> {
+    let offset = path.metadata().map(|m| m.len()).unwrap_or(0);
+
+    let mut request = client.get(url);
+    if offset > 0 {
+        request = request.header(RANGE, format!("bytes={}-", offset));
+    }
+
+    let mut response = request.send().map_err(|e| match e.is_builder() {
+        true => fatal(e),
+        false => transient(e),
+    })?;
+    let status = response.status();
+
+    if status == StatusCode::RANGE_NOT_SATISFIABLE {
+        // partial file is stale, start from scratch on next attempt
+        std::fs::remove_file(path).map_err(fatal)?;
+        return Err(transient(response.error_for_status().unwrap_err()));
+    } else if status == StatusCode::NOT_FOUND {
+        return Err(Failure::Fatal(DownloadError::NotFound));
+    } else if status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS {
+        return Err(transient(response.error_for_status().unwrap_err()));
+    } else if !status.is_success() {
+        return Err(fatal(response.error_for_status().unwrap_err()));
+    }
+
+    let (mut file, mut downloaded) = if status == StatusCode::PARTIAL_CONTENT {
+        let file = OpenOptions::new().append(true).open(path).map_err(fatal)?;
+        (file, offset)
+    } else {
+        (File::create(path).map_err(fatal)?, 0)
+    };
+
+    let total = response.content_length().map(|l| l + downloaded);
+    if let Some(progress) = progress {
+        progress.start(name, total);
+        progress.update(downloaded);
+    }
+
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        let read = response.read(&mut buf).map_err(transient)?;
+        if read == 0 {
+            break;
+        }
+
+        file.write_all(&buf[..read]).map_err(fatal)?;
+        downloaded += read as u64;
+        if let Some(progress) = progress {
+            progress.update(downloaded);
+        }
+    }
+
+    file.flush().map_err(fatal)?;
+    if let Some(total) = total {
+        if downloaded < total {
+            let err = io::Error::new(io::ErrorKind::UnexpectedEof, "truncated response");
+            return Err(transient(err));
+        }
+    }
+
+    if let Some(progress) = progress {
+        progress.finish();
+    }
+
+    Ok(())
+}
+
+fn transient<E: Into<DownloadError>>(e: E) -> Failure {
+    Failure::Transient(e.into())
+}
+
+fn fatal<E: Into<DownloadError>>(e: E) -> Failure {
+    Failure::Fatal(e.into())
+}
+
+impl Default for Retry {
+    fn default() -> Self {
+        Retry {
+            retries: 3,
+            backoff: Duration::from_secs(1),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, sync::Mutex};
+
+    use super::*;
+    use crate::{
+        protoc::provider::test_server::{self, Response, TestServer},
+        temp::TempDir,
+    };
+
+    const RETRY: Retry = Retry {
+        retries: 2,
+        backoff: Duration::from_millis(1),
+    };
+
+    /// Records calls of `DownloadProgress`.
+    #[derive(Default)]
+    struct Recorder {
+        calls: Mutex<Vec<String>>,
+    }
+
+    impl DownloadProgress for Recorder {
+        fn start(&self, name: &str, total: Option<u64>) {
+            let call = format!("start {} {:?}", name, total);
+            self.calls.lock().unwrap().push(call);
+        }
+
+        fn update(&self, downloaded: u64) {
+            let call = format!("update {}", downloaded);
+            self.calls.lock().unwrap().push(call);
+        }
+
+        fn finish(&self) {
+            self.calls.lock().unwrap().push("finish".to_owned());
+        }
+    }
+
+    #[test]
+    fn retries_server_errors() {
+        let server = TestServer::start(vec![
+            Response::new(503, b""),
+            Response::new(500, b""),
+            Response::new(200, b"archive"),
+        ]);
+
+        let dir = TempDir::new("protogen-test-http-retry").unwrap();
+        let path = dir.path().join("protoc.zip");
+        let url = server.url("/protoc.zip");
+        fetch(
+            &test_server::client(),
+            &url,
+            "protoc.zip",
+            &path,
+            RETRY,
+            None,
+        )
+        .unwrap();
+
+        assert_eq!(fs::read(&path).unwrap(), b"archive");
+        assert_eq!(server.finish().len(), 3);
+    }
+
+    #[test]
+    fn gives_up_after_retries() {
+        let responses = vec![Response::new(502, b""); 3];
+        let server = TestServer::start(responses);
+
+        let dir = TempDir::new("protogen-test-http-give-up").unwrap();
+        let path = dir.path().join("protoc.zip");
+        let url = server.url("/protoc.zip");
+        let result = fetch(
+            &test_server::client(),
+            &url,
+            "protoc.zip",
+            &path,
+            RETRY,
+            None,
+        );
+
+        assert!(matches!(result, Err(DownloadError::Request(_))));
+        assert_eq!(server.finish().len(), 3);
+    }
+
+    #[test]
+    fn resumes_truncated_download() {
+        let server = TestServer::start(vec![
+            Response::new(200, b"0123456789").truncate(4),
+            Response::new(206, b"456789").header("Content-Range", "bytes 4-9/10"),
+        ]);
+
+        let dir = TempDir::new("protogen-test-http-resume").unwrap();
+        let path = dir.path().join("protoc.zip");
+        let url = server.url("/protoc.zip");
+        let progress: Arc<dyn DownloadProgress> = Arc::new(Recorder::default());
+        let client = test_server::client();
+        fetch(&client, &url, "protoc.zip", &path, RETRY, Some(&progress)).unwrap();
+
+        assert_eq!(fs::read(&path).unwrap(), b"0123456789");
+
+        let requests = server.finish();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[0].header("range"), None);
+        assert_eq!(requests[1].header("range"), Some("bytes=4-"));
+    }
+
+    #[test]
+    fn reports_progress() {
+        let server = TestServer::start(vec![Response::new(200, b"archive")]);
+
+        let dir = TempDir::new("protogen-test-http-progress").unwrap();
+        let path = dir.path().join("protoc.zip");
+        let url = server.url("/protoc.zip");
+        let recorder = Arc::new(Recorder::default());
+        let progress: Arc<dyn DownloadProgress> = recorder.clone();
+        let client = test_server::client();
+        fetch(&client, &url, "protoc.zip", &path, RETRY, Some(&progress)).unwrap();
+        server.finish();
+
+        let calls = recorder.calls.lock().unwrap();
+        assert_eq!(calls.first().unwrap(), "start protoc.zip Some(7)");
+        assert_eq!(calls[1], "update 0");
+        assert_eq!(calls[calls.len() - 2], "update 7");
+        assert_eq!(calls.last().unwrap(), "finish");
+    }
+}
@@ -1,25 +1,102 @@
 use std::path::PathBuf;
 
-pub fn push_binary_path(path: &mut PathBuf) {
+use semver::{Identifier, Version};
+
+use crate::config;
+
+pub fn push_binary_path(path: &mut PathBuf, platform: &str) {
     path.push("bin");
-    path.push(binary_name());
+    match platform.starts_with("win") {
+        true => path.push("protoc.exe"),
+        false => path.push("protoc"),
+    }
 }
 
 pub fn push_include_path(path: &mut PathBuf) {
     path.push("include")
 }
 
+/// Pushes path of a file which marks complete installation.
+pub fn push_stamp_path(path: &mut PathBuf) {
+    path.push(".protogen-installed")
+}
+
+/// Pushes path of a file which records when protoc was last used.
+pub fn push_used_path(path: &mut PathBuf) {
+    path.push(".protogen-used")
+}
+
+/// Pushes path of the directory with cached protoc versions.
+pub fn push_cache_path(path: &mut PathBuf) {
+    path.push("protogen");
+    path.push("protoc");
+}
+
 pub fn target_platform() -> &'static str {
     platform()
 }
 
+/// Returns platforms to try in order if there is no release for `platform`.
+pub fn fallback_platforms(platform: &str) -> &'static [&'static str] {
+    match platform {
+        // older releases run under Rosetta
+        "osx-aarch_64" => &["osx-universal_binary", "osx-x86_64"],
+        "osx-x86_64" => &["osx-universal_binary"],
+        _ => &[],
+    }
+}
+
+/// Returns name of a release archive as published on GitHub.
+pub fn archive_name(tag: &str, platform: &str) -> String {
+    format!("{}.zip", archive_stem(tag, platform))
+}
+
+/// Returns name of a release archive without extension.
+///
+/// Release candidates are named like `protoc-3.20.0-rc-2-linux-x86_64` while
+/// their tag is `v3.20.0-rc2`.
+pub fn archive_stem(tag: &str, platform: &str) -> String {
+    let mut version = tag.trim_start_matches('v').to_owned();
+    if let Some(idx) = version.find("-rc") {
+        if !version[idx..].starts_with("-rc-") {
+            version.insert(idx + 3, '-');
+        }
+    }
+
+    format!("protoc-{}-{}", version, platform)
+}
+
+/// Returns tag of the protoc release, e.g. `v3.12.3`, `v21.12` or `v22.0-rc1`.
+pub fn release_tag(version: &Version) -> String {
+    let mut tag = match version.major {
+        // releases since 21.0 don't have patch number unless it's set
+        major if major > 3 && version.patch == 0 => format!("v{}.{}", major, version.minor),
+        major => format!("v{}.{}.{}", major, version.minor, version.patch),
+    };
+
+    for (idx, pre) in version.pre.iter().enumerate() {
+        tag.push(if idx == 0 { '-' } else { '.' });
+        match pre {
+            Identifier::Numeric(n) => tag.push_str(&n.to_string()),
+            Identifier::AlphaNumeric(s) => tag.push_str(s),
+        }
+    }
+
+    tag
+}
+
+/// Returns version of the protoc release `tag`.
+pub fn tag_version(tag: &str) -> Option<Version> {
+    config::parse_version(&tag.replace("-rc-", "-rc")).ok()
+}
+
 #[cfg(target_os = "windows")]
-fn binary_name() -> &'static str {
+pub fn binary_name() -> &'static str {
     "protoc.exe"
 }
 
 #[cfg(not(target_os = "windows"))]
-fn binary_name() -> &'static str {
+pub fn binary_name() -> &'static str {
     "protoc"
 }
 
@@ -52,3 +129,111 @@ fn platform() -> &'static str {
 fn platform() -> &'static str {
     "osx-x86_32"
 }
+
+#[cfg(all(target_os = "linux", target_arch = "aarch64"))]
+fn platform() -> &'static str {
+    "linux-aarch_64"
+}
+
+#[cfg(all(
+    target_os = "linux",
+    target_arch = "powerpc64",
+    target_endian = "little"
+))]
+fn platform() -> &'static str {
+    "linux-ppcle_64"
+}
+
+#[cfg(all(target_os = "linux", target_arch = "s390x"))]
+fn platform() -> &'static str {
+    "linux-s390_64"
+}
+
+#[cfg(all(target_os = "macos", target_arch = "aarch64"))]
+fn platform() -> &'static str {
+    "osx-aarch_64"
+}
+
+// there are no protoc releases for the rest, the platform must be set manually
+#[cfg(not(any(
+    all(
+        target_os = "windows",
+        any(target_arch = "x86_64", target_arch = "x86")
+    ),
+    all(
+        target_os = "linux",
+        any(
+            target_arch = "x86_64",
+            target_arch = "x86",
+            target_arch = "aarch64",
+            target_arch = "s390x",
+            all(target_arch = "powerpc64", target_endian = "little")
+        )
+    ),
+    all(
+        target_os = "macos",
+        any(target_arch = "x86_64", target_arch = "x86", target_arch = "aarch64")
+    ),
+)))]
+fn platform() -> &'static str {
+    "unknown"
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn maps_versions_to_release_tags() {
+        let cases = [
+            ("3.12.3", "v3.12.3"),
+            ("3.20.0-rc2", "v3.20.0-rc2"),
+            ("21.12.0", "v21.12"),
+            ("21.12.1", "v21.12.1"),
+            ("22.0.0-rc1", "v22.0-rc1"),
+        ];
+
+        for (version, tag) in &cases {
+            let version = Version::parse(version).unwrap();
+            assert_eq!(release_tag(&version), *tag);
+            assert_eq!(tag_version(tag), Some(version));
+        }
+    }
+
+    #[test]
+    fn maps_release_tags_to_archive_stems() {
+        let cases = [
+            ("v3.12.3", "protoc-3.12.3-linux-x86_64"),
+            ("v3.20.0-rc2", "protoc-3.20.0-rc-2-linux-x86_64"),
+            ("v3.20.0-rc-2", "protoc-3.20.0-rc-2-linux-x86_64"),
+            ("v21.12", "protoc-21.12-linux-x86_64"),
+            ("v22.0-rc1", "protoc-22.0-rc-1-linux-x86_64"),
+        ];
+
+        for (tag, stem) in &cases {
+            assert_eq!(archive_stem(tag, "linux-x86_64"), *stem);
+        }
+
+        assert_eq!(
+            archive_name("v21.12", "win64"),
+            "protoc-21.12-win64.zip".to_owned()
+        );
+    }
+
+    #[test]
+    fn parses_tag_versions() {
+        let cases = [
+            ("v3.20.0-rc-2", Some("3.20.0-rc2")),
+            ("v21.12", Some("21.12.0")),
+            ("v22.0-rc1", Some("22.0.0-rc1")),
+            ("v2.6.1", Some("2.6.1")),
+            ("v3.12", None),
+            ("weekly", None),
+        ];
+
+        for (tag, version) in &cases {
+            let version = version.map(|v| Version::parse(v).unwrap());
+            assert_eq!(tag_version(tag), version, "{}", tag);
+        }
+    }
+}
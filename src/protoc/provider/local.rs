@@ -0,0 +1,167 @@
+use std::{
+    ffi::OsStr,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use log::info;
+
+use super::{archive, layout, DownloadError, ProtocDownloader};
+
+/// Downloader that copies protoc releases from a local directory or archive.
+///
+/// Directories are searched recursively for an archive named like the one
+/// published on GitHub, e.g. `protoc-3.12.3-linux-x86_64.zip` or
+/// `protoc-3.12.3-linux-x86_64.tar.gz`. A single archive must be named like that
+/// as well.
+#[derive(Debug, Default)]
+pub struct LocalDownloader {
+    path: Option<PathBuf>,
+}
+
+impl LocalDownloader {
+    pub fn new<P: Into<PathBuf>>(path: P) -> Self {
+        Self {
+            path: Some(path.into()),
+        }
+    }
+
+    fn find_archive(&self, stem: &str) -> Result<Option<PathBuf>, DownloadError> {
+        let root = match self.path {
+            Some(ref path) => path,
+            None => return Ok(None),
+        };
+
+        // a single archive still has to be the requested release
+        if root.is_file() {
+            let matches = root.file_name().is_some_and(|n| is_archive(n, stem));
+            return Ok(Some(root.clone()).filter(|_| matches));
+        }
+
+        let mut dirs = vec![root.clone()];
+        while let Some(dir) = dirs.pop() {
+            for entry in fs::read_dir(&dir)? {
+                let entry = entry?;
+                let file_type = entry.file_type()?;
+
+                if file_type.is_dir() {
+                    dirs.push(entry.path());
+                } else if file_type.is_file() && is_archive(&entry.file_name(), stem) {
+                    return Ok(Some(entry.path()));
+                }
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+impl ProtocDownloader for LocalDownloader {
+    fn download(&self, tag: &str, platform: &str, path: &Path) -> Result<String, DownloadError> {
+        let stem = layout::archive_stem(tag, platform);
+
+        let source = match self.find_archive(&stem)? {
+            Some(source) => source,
+            None => {
+                return Err(DownloadError::Unavailable {
+                    tag: tag.to_owned(),
+                    platform: platform.to_owned(),
+                    archives: self.path.clone(),
+                })
+            }
+        };
+
+        let name = match source.file_name().and_then(|n| n.to_str()) {
+            Some(name) => name.to_owned(),
+            None => layout::archive_name(tag, platform),
+        };
+
+        let mut out_path = PathBuf::from(path);
+        out_path.push(&name);
+        info!("copying {:?}", source);
+        fs::copy(&source, &out_path)?;
+
+        Ok(name)
+    }
+}
+
+/// Returns whether `name` is an archive named `stem` with a known extension.
+fn is_archive(name: &OsStr, stem: &str) -> bool {
+    let name = match name.to_str().and_then(|n| n.strip_prefix(stem)) {
+        Some(ext) => ext,
+        None => return false,
+    };
+
+    match name.strip_prefix('.') {
+        Some(ext) => archive::EXTENSIONS.contains(&ext),
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::temp::TempDir;
+
+    const STEM: &str = "protoc-3.12.3-linux-x86_64";
+
+    fn write_archive(path: &Path) {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, "archive").unwrap();
+    }
+
+    #[test]
+    fn finds_archive_in_nested_directory() {
+        let dir = TempDir::new("protogen-test-local-search").unwrap();
+        let archives = dir.path().join("archives");
+        write_archive(&archives.join("protoc-3.12.2-linux-x86_64.zip"));
+        write_archive(&archives.join(format!("{}.zip.sha256", STEM)));
+        write_archive(&archives.join(format!("3.12/{}.tar.gz", STEM)));
+
+        let out = dir.path().join("out");
+        fs::create_dir(&out).unwrap();
+        let downloader = LocalDownloader::new(&archives);
+        let name = downloader
+            .download("v3.12.3", "linux-x86_64", &out)
+            .unwrap();
+
+        assert_eq!(name, format!("{}.tar.gz", STEM));
+        assert_eq!(fs::read(out.join(name)).unwrap(), b"archive");
+    }
+
+    #[test]
+    fn refuses_single_archive_of_another_release() {
+        let dir = TempDir::new("protogen-test-local-single").unwrap();
+        let archive = dir.path().join("protoc-3.12.2-linux-x86_64.zip");
+        write_archive(&archive);
+
+        let downloader = LocalDownloader::new(&archive);
+        match downloader.download("v3.12.3", "linux-x86_64", dir.path()) {
+            Err(DownloadError::Unavailable { tag, archives, .. }) => {
+                assert_eq!(tag, "v3.12.3");
+                assert_eq!(archives, Some(archive.clone()));
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+
+        let out = dir.path().join("out");
+        fs::create_dir(&out).unwrap();
+        let name = downloader
+            .download("v3.12.2", "linux-x86_64", &out)
+            .unwrap();
+        assert_eq!(name, "protoc-3.12.2-linux-x86_64.zip");
+    }
+
+    #[test]
+    fn reports_offline_without_archives() {
+        let dir = TempDir::new("protogen-test-local-offline").unwrap();
+        let error = LocalDownloader::default()
+            .download("v3.12.3", "linux-x86_64", dir.path())
+            .unwrap_err();
+
+        assert_eq!(
+            error.to_string(),
+            "protoc v3.12.3 for linux-x86_64 isn't available offline"
+        );
+    }
+}
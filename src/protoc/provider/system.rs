@@ -0,0 +1,157 @@
+use std::{
+    env, error, fmt, io,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use log::debug;
+use semver::Version;
+
+use super::layout;
+
+#[derive(Debug)]
+pub enum SystemError {
+    /// Protoc binary wasn't found in `PATH`.
+    NotFound,
+    Io(io::Error),
+    /// `protoc --version` returned something unexpected.
+    UnknownVersion(String),
+    VersionMismatch {
+        found: Version,
+        required: String,
+    },
+}
+
+/// Provides protoc binary installed in the system, e.g. by a package manager.
+#[derive(Debug)]
+pub struct SystemProvider {
+    binary_path: PathBuf,
+    include_path: Option<PathBuf>,
+    version: Version,
+}
+
+impl SystemProvider {
+    /// Probes protoc binary at `path`.
+    pub fn new<P: Into<PathBuf>>(path: P) -> Result<Self, SystemError> {
+        let binary_path = path.into();
+        let output = Command::new(&binary_path).arg("--version").output()?;
+        let raw_version = String::from_utf8_lossy(&output.stdout);
+
+        let version = match parse_version(&raw_version) {
+            Some(version) if output.status.success() => version,
+            _ => return Err(SystemError::UnknownVersion(raw_version.trim().to_owned())),
+        };
+
+        let include_path = find_include_path(&binary_path);
+        debug!(
+            "protoc at {:?} is {}, includes {:?}",
+            binary_path, version, include_path
+        );
+
+        Ok(Self {
+            binary_path,
+            include_path,
+            version,
+        })
+    }
+
+    /// Looks up protoc binary in `PATH` and probes it.
+    pub fn find() -> Result<Self, SystemError> {
+        Self::new(Self::which()?)
+    }
+
+    /// Looks up protoc binary in `PATH` without running it.
+    pub fn which() -> Result<PathBuf, SystemError> {
+        let paths = env::var_os("PATH").ok_or(SystemError::NotFound)?;
+        for mut path in env::split_paths(&paths) {
+            path.push(layout::binary_name());
+            if path.is_file() {
+                return Ok(path);
+            }
+        }
+
+        Err(SystemError::NotFound)
+    }
+
+    /// Returns directory with well-known protos for protoc at `binary_path`.
+    pub fn find_include_path(binary_path: &Path) -> Option<PathBuf> {
+        find_include_path(binary_path)
+    }
+
+    pub fn version(&self) -> &Version {
+        &self.version
+    }
+
+    pub fn binary_path(&self) -> Option<&Path> {
+        Some(self.binary_path.as_path())
+    }
+
+    pub fn include_path(&self) -> Option<&Path> {
+        self.include_path.as_deref()
+    }
+}
+
+/// Parses output of `protoc --version`, e.g. `libprotoc 3.12.3`.
+///
+/// Since 3.21 protobuf omits patch version, e.g. `libprotoc 23.4`.
+fn parse_version(output: &str) -> Option<Version> {
+    let raw = output.split_whitespace().last()?;
+    let (core, pre) = match raw.find('-') {
+        Some(idx) => raw.split_at(idx),
+        None => (raw, ""),
+    };
+
+    let mut version = core.to_owned();
+    if core.matches('.').count() == 1 {
+        version.push_str(".0");
+    }
+
+    version.push_str(pre);
+    Version::parse(&version).ok()
+}
+
+/// Finds directory with well-known protos shipped alongside the binary.
+fn find_include_path(binary_path: &Path) -> Option<PathBuf> {
+    let mut candidates = vec![];
+
+    // symlinks are followed to handle layouts like Homebrew's
+    if let Ok(path) = binary_path.canonicalize() {
+        if let Some(prefix) = path.parent().and_then(|p| p.parent()) {
+            candidates.push(prefix.join("include"));
+        }
+    }
+
+    if let Some(prefix) = binary_path.parent().and_then(|p| p.parent()) {
+        candidates.push(prefix.join("include"));
+    }
+
+    candidates.push(PathBuf::from("/usr/local/include"));
+    candidates.push(PathBuf::from("/usr/include"));
+
+    candidates
+        .into_iter()
+        .find(|p| p.join("google/protobuf/descriptor.proto").is_file())
+}
+
+impl From<io::Error> for SystemError {
+    fn from(e: io::Error) -> Self {
+        SystemError::Io(e)
+    }
+}
+
+impl fmt::Display for SystemError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SystemError::NotFound => write!(f, "protoc not found in PATH"),
+            SystemError::Io(e) => e.fmt(f),
+            SystemError::UnknownVersion(v) => write!(f, "unknown protoc version: {:?}", v),
+            SystemError::VersionMismatch { found, required } => write!(
+                f,
+                "protoc {} doesn't match required version {}",
+                found, required
+            ),
+        }
+    }
+}
+
+impl error::Error for SystemError {}
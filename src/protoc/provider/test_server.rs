@@ -0,0 +1,140 @@
+use std::{
+    io::{BufRead, BufReader, Write},
+    net::{SocketAddr, TcpListener},
+    sync::{Arc, Mutex},
+    thread::{self, JoinHandle},
+};
+
+use reqwest::blocking::Client;
+
+/// Canned response of `TestServer`.
+#[derive(Debug, Clone)]
+pub struct Response {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+    /// Number of body bytes sent before the connection is dropped.
+    pub sent: Option<usize>,
+}
+
+/// Request received by `TestServer`.
+#[derive(Debug, Clone)]
+pub struct Request {
+    pub path: String,
+    pub headers: Vec<(String, String)>,
+}
+
+/// HTTP server on localhost answering consecutive requests with canned
+/// responses, one connection per request.
+pub struct TestServer {
+    addr: SocketAddr,
+    requests: Arc<Mutex<Vec<Request>>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Response {
+    pub fn new(status: u16, body: &[u8]) -> Self {
+        Self {
+            status,
+            headers: vec![],
+            body: body.to_vec(),
+            sent: None,
+        }
+    }
+
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.to_owned(), value.to_owned()));
+        self
+    }
+
+    /// Drops the connection after `sent` bytes of the body.
+    pub fn truncate(mut self, sent: usize) -> Self {
+        self.sent = Some(sent);
+        self
+    }
+}
+
+impl Request {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+impl TestServer {
+    pub fn start(responses: Vec<Response>) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("can't bind test server");
+        let addr = listener.local_addr().unwrap();
+        let requests = Arc::new(Mutex::new(vec![]));
+
+        let received = Arc::clone(&requests);
+        let handle = thread::spawn(move || {
+            for response in responses {
+                let (mut stream, _) = match listener.accept() {
+                    Ok(connection) => connection,
+                    Err(_) => return,
+                };
+
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                let path = line.split_whitespace().nth(1).unwrap_or("").to_owned();
+
+                let mut headers = vec![];
+                loop {
+                    line.clear();
+                    reader.read_line(&mut line).unwrap();
+                    match line.trim_end().split_once(':') {
+                        Some((name, value)) => {
+                            headers.push((name.to_owned(), value.trim().to_owned()))
+                        }
+                        None => break,
+                    }
+                }
+
+                received.lock().unwrap().push(Request { path, headers });
+
+                let mut head = format!(
+                    "HTTP/1.1 {} Test\r\nContent-Length: {}\r\nConnection: close\r\n",
+                    response.status,
+                    response.body.len()
+                );
+
+                for (name, value) in &response.headers {
+                    head.push_str(&format!("{}: {}\r\n", name, value));
+                }
+
+                head.push_str("\r\n");
+                let sent = response.sent.unwrap_or(response.body.len());
+                let _ = stream.write_all(head.as_bytes());
+                let _ = stream.write_all(&response.body[..sent]);
+            }
+        });
+
+        Self {
+            addr,
+            requests,
+            handle: Some(handle),
+        }
+    }
+
+    pub fn url(&self, path: &str) -> String {
+        format!("http://{}{}", self.addr, path)
+    }
+
+    /// Waits until all responses are served and returns received requests.
+    pub fn finish(mut self) -> Vec<Request> {
+        if let Some(handle) = self.handle.take() {
+            handle.join().expect("test server panicked");
+        }
+
+        self.requests.lock().unwrap().clone()
+    }
+}
+
+/// Returns HTTP client which doesn't go through proxies set in environment.
+pub fn client() -> Client {
+    Client::builder().no_proxy().build().unwrap()
+}
@@ -0,0 +1,137 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use reqwest::blocking::Client;
+
+use super::{
+    http::{self, DownloadProgress, Retry},
+    layout, ArchiveKind, DownloadError, ProtocDownloader,
+};
+
+/// Downloader that fetches protoc releases by a URL template.
+///
+/// Unlike `GithubDownloader` it doesn't use GitHub's releases API, so it can
+/// be pointed to any HTTP server. Supported placeholders are `{tag}` (e.g.
+/// `v3.12.3`), `{version}` (e.g. `3.12.3`), `{platform}` and `{name}` (e.g.
+/// `protoc-3.12.3-linux-x86_64.zip`). The archive format is detected from the
+/// URL, so the template may point to tarballs as well.
+pub struct UrlDownloader {
+    client: Client,
+    template: String,
+    retry: Retry,
+    progress: Option<Arc<dyn DownloadProgress>>,
+}
+
+impl UrlDownloader {
+    pub fn new<S: Into<String>>(client: Client, template: S) -> Self {
+        Self {
+            client,
+            template: template.into(),
+            retry: Retry::default(),
+            progress: None,
+        }
+    }
+
+    pub fn with_template<S: Into<String>>(template: S) -> Self {
+        let client = Client::builder()
+            .gzip(true)
+            .connect_timeout(Duration::from_secs(30))
+            .build()
+            .expect("Failed to create HTTP client");
+
+        UrlDownloader::new(client, template)
+    }
+
+    pub fn set_retry(&mut self, retry: Retry) {
+        self.retry = retry;
+    }
+
+    fn url(&self, tag: &str, platform: &str, name: &str) -> String {
+        self.template
+            .replace("{tag}", tag)
+            .replace("{version}", tag.trim_start_matches('v'))
+            .replace("{platform}", platform)
+            .replace("{name}", name)
+    }
+}
+
+impl ProtocDownloader for UrlDownloader {
+    fn download(&self, tag: &str, platform: &str, path: &Path) -> Result<String, DownloadError> {
+        let name = layout::archive_name(tag, platform);
+        let url = self.url(tag, platform, &name);
+
+        // keep name of the archive the URL points to, e.g. a tarball
+        let file_name = url.rsplit('/').next().unwrap_or("");
+        let name = match ArchiveKind::from_name(file_name) {
+            Some(_) => file_name.to_owned(),
+            None => name,
+        };
+
+        let mut path = PathBuf::from(path);
+        path.push(&name);
+
+        http::fetch(
+            &self.client,
+            &url,
+            &name,
+            &path,
+            self.retry,
+            self.progress.as_ref(),
+        )?;
+
+        Ok(name)
+    }
+
+    fn set_progress(&mut self, progress: Arc<dyn DownloadProgress>) {
+        self.progress = Some(progress);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::{
+        protoc::provider::test_server::{self, Response, TestServer},
+        temp::TempDir,
+    };
+
+    #[test]
+    fn expands_template() {
+        let template = "https://mirror/{tag}/{version}/{platform}/{name}";
+        let downloader = UrlDownloader::new(test_server::client(), template);
+        let name = layout::archive_name("v3.12.3", "linux-x86_64");
+
+        assert_eq!(
+            downloader.url("v3.12.3", "linux-x86_64", &name),
+            "https://mirror/v3.12.3/3.12.3/linux-x86_64/protoc-3.12.3-linux-x86_64.zip"
+        );
+    }
+
+    #[test]
+    fn downloads_from_template_url() {
+        let server = TestServer::start(vec![Response::new(200, b"tarball")]);
+        let template = server.url("/protoc/{version}/protoc-{platform}.tar.gz");
+        let downloader = UrlDownloader::new(test_server::client(), template);
+        let dir = TempDir::new("protogen-test-url").unwrap();
+
+        let name = downloader
+            .download("v21.12", "linux-aarch_64", dir.path())
+            .unwrap();
+
+        assert_eq!(name, "protoc-linux-aarch_64.tar.gz");
+        assert_eq!(fs::read(dir.path().join(&name)).unwrap(), b"tarball");
+
+        let requests = server.finish();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(
+            requests[0].path,
+            "/protoc/21.12/protoc-linux-aarch_64.tar.gz"
+        );
+        assert_eq!(requests[0].header("range"), None);
+    }
+}
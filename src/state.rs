@@ -0,0 +1,118 @@
+use std::{
+    error, fmt, fs, io,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+/// Name of the file with results of the previous run placed next to
+/// `protogen.toml`.
+pub const STATE_FILE: &str = ".protogen-state";
+
+#[derive(Debug)]
+pub enum StateError {
+    Io(io::Error),
+    Serialize(toml::ser::Error),
+}
+
+/// Fingerprints of pages compiled by the previous run and files they produced.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct State {
+    /// Files not generated anymore, kept since their output isn't cleaned.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub kept: Vec<PathBuf>,
+    #[serde(default)]
+    pub pages: Vec<PageState>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct PageState {
+    pub page: PathBuf,
+    pub plugins: Vec<String>,
+    /// Hash of everything the generated code depends on.
+    pub fingerprint: Option<String>,
+    /// Files generated for the page, relative to the root.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub files: Vec<PathBuf>,
+}
+
+impl State {
+    /// Reads a state file at `path` or returns an empty state if it doesn't
+    /// exist or is unreadable.
+    pub fn load(path: &Path) -> Self {
+        let buf = match fs::read(path) {
+            Ok(buf) => buf,
+            Err(_) => return State::default(),
+        };
+
+        toml::from_slice(&buf).unwrap_or_default()
+    }
+
+    pub fn save(&mut self, path: &Path) -> Result<(), StateError> {
+        self.pages
+            .sort_by(|a, b| (&a.page, &a.plugins).cmp(&(&b.page, &b.plugins)));
+
+        let mut content = String::from("# This file is generated by protogen. Do not edit it.\n\n");
+        content.push_str(&toml::to_string(self)?);
+        fs::write(path, content)?;
+        Ok(())
+    }
+
+    /// Returns state of the `page` compiled by `plugins`.
+    pub fn page(&self, page: &Path, plugins: &[String]) -> Option<&PageState> {
+        self.pages
+            .iter()
+            .find(|p| p.page == page && p.plugins == plugins)
+    }
+
+    pub fn set_page(&mut self, state: PageState) {
+        self.pages
+            .retain(|p| p.page != state.page || p.plugins != state.plugins);
+        self.pages.push(state);
+    }
+
+    /// Returns files generated for all pages followed by kept ones.
+    pub fn files(&self) -> impl Iterator<Item = &Path> {
+        self.pages
+            .iter()
+            .flat_map(|p| &p.files)
+            .chain(&self.kept)
+            .map(PathBuf::as_path)
+    }
+}
+
+impl PageState {
+    pub fn new(page: &Path, plugins: &[String]) -> Self {
+        PageState {
+            page: page.to_owned(),
+            plugins: plugins.to_owned(),
+            fingerprint: None,
+            files: vec![],
+        }
+    }
+}
+
+impl From<io::Error> for StateError {
+    fn from(e: io::Error) -> Self {
+        StateError::Io(e)
+    }
+}
+
+impl From<toml::ser::Error> for StateError {
+    fn from(e: toml::ser::Error) -> Self {
+        StateError::Serialize(e)
+    }
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StateError::Io(e) => e.fmt(f),
+            StateError::Serialize(e) => write!(f, "failed to serialize state file: {}", e),
+        }
+    }
+}
+
+impl error::Error for StateError {}
@@ -0,0 +1,48 @@
+use std::{
+    env, fs, io,
+    path::{Path, PathBuf},
+    process,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// Temporary directory removed on drop.
+#[derive(Debug)]
+pub struct TempDir {
+    path: PathBuf,
+}
+
+impl TempDir {
+    /// Creates a new directory in the system temporary directory.
+    ///
+    /// Existing directories, e.g. left by a process with the same id, are
+    /// skipped rather than reused.
+    pub fn new(prefix: &str) -> io::Result<Self> {
+        loop {
+            let name = format!(
+                "{}-{}-{}",
+                prefix,
+                process::id(),
+                COUNTER.fetch_add(1, Ordering::SeqCst)
+            );
+
+            let path = env::temp_dir().join(name);
+            match fs::create_dir(&path) {
+                Ok(()) => return Ok(Self { path }),
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
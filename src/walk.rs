@@ -6,19 +6,31 @@ use std::{
     fs::{self, ReadDir},
     io,
     marker::PhantomData,
-    path::PathBuf,
-    rc::Rc,
+    path::{Path, PathBuf},
+    sync::Arc,
 };
 
+use log::{debug, trace};
+
 // type Walker = impl Iterator is not supported yet
 pub trait Walker: Iterator<Item = io::Result<PathBuf>> {}
 
+/// Kind of a page yielded by `PagingProtoWalker`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PageKind {
+    /// Protos placed directly in the root directory.
+    Root,
+    /// Protos of a root's subdirectory and all of its descendants.
+    Directory,
+}
+
 #[derive(Debug)]
 pub struct PagingProtoWalker<F, W> {
     path: PathBuf,
     make_walker: F,
     content: Option<ReadDir>,
-    exclude: Option<Rc<HashSet<PathBuf>>>,
+    root_paged: bool,
+    exclude: Option<Arc<HashSet<PathBuf>>>,
     _fret: PhantomData<W>,
 }
 
@@ -28,6 +40,7 @@ impl<F, W> PagingProtoWalker<F, W> {
             path: path.into(),
             make_walker,
             content: None,
+            root_paged: false,
             exclude: None,
             _fret: PhantomData,
         }
@@ -39,9 +52,8 @@ impl<F, W> PagingProtoWalker<F, W> {
         P: Into<PathBuf>,
     {
         let mut exclude = HashSet::new();
-        match iter.size_hint() {
-            (x, Some(y)) => exclude.reserve(y - x),
-            _ => (),
+        if let (x, Some(y)) = iter.size_hint() {
+            exclude.reserve(y - x)
         }
 
         for path in iter {
@@ -56,26 +68,55 @@ impl<F, W> PagingProtoWalker<F, W> {
             exclude.insert(absolute_path);
         }
 
-        self.exclude = Some(Rc::new(exclude));
+        self.exclude = Some(Arc::new(exclude));
         Ok(())
     }
+
+    /// Returns path and kind of the page a file at `path` belongs to.
+    ///
+    /// Returns `None` if the file is outside of the root or excluded.
+    pub fn page_of(&self, path: &Path) -> Option<(PathBuf, PageKind)> {
+        let relative = path.strip_prefix(&self.path).ok()?;
+        if let Some(ref exclude) = self.exclude {
+            if path.ancestors().any(|p| exclude.contains(p)) {
+                return None;
+            }
+        }
+
+        let mut components = relative.components();
+        let first = components.next()?;
+        match components.next() {
+            Some(_) => Some((self.path.join(first), PageKind::Directory)),
+            None => Some((self.path.clone(), PageKind::Root)),
+        }
+    }
 }
 
 impl<F, W> Iterator for PagingProtoWalker<F, W>
 where
-    F: Fn(PathBuf, Rc<HashSet<PathBuf>>) -> W,
+    F: Fn(PathBuf, PageKind, Arc<HashSet<PathBuf>>) -> W,
     W: Walker,
 {
     type Item = io::Result<W>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        if self.content.is_none() {
-            let dir = self.path.canonicalize().and_then(|p| fs::read_dir(p));
+        if !self.root_paged {
+            let root = match self.path.canonicalize() {
+                Err(e) => return Some(Err(e)),
+                Ok(p) => p,
+            };
 
-            match dir {
+            match fs::read_dir(&root) {
                 Err(e) => return Some(Err(e)),
                 Ok(c) => self.content = Some(c),
             };
+
+            self.root_paged = true;
+            let make = &self.make_walker;
+            let exclude = self.exclude.get_or_insert_with(|| Arc::new(HashSet::new()));
+            debug!("paging protos in {:?}", root);
+            let walker = make(root, PageKind::Root, Arc::clone(exclude));
+            return Some(Ok(walker));
         }
 
         loop {
@@ -91,9 +132,14 @@ where
                         continue;
                     }
 
+                    let exclude = self.exclude.get_or_insert_with(|| Arc::new(HashSet::new()));
+                    if exclude.contains(&entry.path()) {
+                        trace!("skipping excluded {:?}", entry.path());
+                        continue;
+                    }
+
                     let make = &self.make_walker;
-                    let exclude = self.exclude.get_or_insert_with(|| Rc::new(HashSet::new()));
-                    let walker = make(entry.path(), Rc::clone(exclude));
+                    let walker = make(entry.path(), PageKind::Directory, Arc::clone(exclude));
                     return Some(Ok(walker));
                 }
             }
@@ -110,8 +156,139 @@ where
             path: self.path.clone(),
             make_walker: self.make_walker.clone(),
             content: None,
+            root_paged: false,
             exclude: self.exclude.clone(),
-            _fret: self._fret.clone(),
+            _fret: self._fret,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+    use crate::{temp::TempDir, walk::deep::DeepProtoWalker};
+
+    /// Creates protos in the root, in subdirectories and in nested ones.
+    fn make_layout(prefix: &str) -> TempDir {
+        let dir = TempDir::new(prefix).unwrap();
+        for file in &[
+            "root.proto",
+            "readme.txt",
+            "a/a.proto",
+            "a/nested/deep.proto",
+            "b/b.proto",
+            "excluded/x.proto",
+        ] {
+            let path = dir.path().join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, "syntax = \"proto3\";\n").unwrap();
+        }
+
+        dir
+    }
+
+    /// Returns sorted paths of protos relative to `root`.
+    fn relative<W: Walker>(root: &Path, walker: W) -> Vec<String> {
+        let mut protos: Vec<_> = walker
+            .map(|p| p.unwrap())
+            .map(|p| p.strip_prefix(root).unwrap().to_string_lossy().into_owned())
+            .collect();
+
+        protos.sort();
+        protos
+    }
+
+    /// Returns protos of every page keyed by the page path relative to `root`.
+    fn pages(root: &Path, exclude: &[&str]) -> BTreeMap<String, Vec<String>> {
+        let mut walker = PagingProtoWalker::new(root, |p: PathBuf, k, e| {
+            let mut walker = DeepProtoWalker::new(p, e);
+            walker.set_recursive(k == PageKind::Directory);
+            walker
+        });
+
+        walker.set_exclude(exclude.iter()).unwrap();
+        walker
+            .map(|page| {
+                let page = page.unwrap();
+                let path = page.root().strip_prefix(root).unwrap();
+                let path = path.to_string_lossy().into_owned();
+                (path, relative(root, page))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn pages_root_and_subdirectories() {
+        let dir = make_layout("protogen-test-walk-pages");
+        let root = dir.path().canonicalize().unwrap();
+
+        let mut expected = BTreeMap::new();
+        expected.insert("".to_owned(), vec!["root.proto".to_owned()]);
+        expected.insert(
+            "a".to_owned(),
+            vec!["a/a.proto".to_owned(), "a/nested/deep.proto".to_owned()],
+        );
+        expected.insert("b".to_owned(), vec!["b/b.proto".to_owned()]);
+        expected.insert("excluded".to_owned(), vec!["excluded/x.proto".to_owned()]);
+        assert_eq!(pages(&root, &[]), expected);
+
+        expected.remove("excluded");
+        assert_eq!(pages(&root, &["excluded"]), expected);
+    }
+
+    #[test]
+    fn finds_page_of_files() {
+        let root = Path::new("/project");
+        let mut walker: PagingProtoWalker<_, DeepProtoWalker> =
+            PagingProtoWalker::new(root, |p: PathBuf, _: PageKind, e| {
+                DeepProtoWalker::new(p, e)
+            });
+        walker
+            .set_exclude(["excluded", "a/skipped"].iter())
+            .unwrap();
+        let page_of = |path: &str| walker.page_of(Path::new(path));
+
+        assert_eq!(
+            page_of("/project/root.proto"),
+            Some((root.to_owned(), PageKind::Root))
+        );
+        assert_eq!(
+            page_of("/project/a/nested/deep.proto"),
+            Some((root.join("a"), PageKind::Directory))
+        );
+        assert_eq!(
+            page_of("/project/b/b.proto"),
+            Some((root.join("b"), PageKind::Directory))
+        );
+        assert_eq!(page_of("/project/excluded/x.proto"), None);
+        assert_eq!(page_of("/project/a/skipped/x.proto"), None);
+        assert_eq!(page_of("/other/a/a.proto"), None);
+        assert_eq!(page_of("/project"), None);
+    }
+
+    #[test]
+    fn deep_walker_recursion() {
+        let dir = make_layout("protogen-test-walk-deep");
+        let root = dir.path().canonicalize().unwrap();
+        let exclude = Arc::new(HashSet::new());
+
+        let walker = DeepProtoWalker::new(&root, Arc::clone(&exclude));
+        let all = relative(&root, walker);
+        assert_eq!(
+            all,
+            [
+                "a/a.proto",
+                "a/nested/deep.proto",
+                "b/b.proto",
+                "excluded/x.proto",
+                "root.proto"
+            ]
+        );
+
+        let mut walker = DeepProtoWalker::new(&root, exclude);
+        walker.set_recursive(false);
+        assert_eq!(relative(&root, walker), ["root.proto"]);
+    }
+}
@@ -1,4 +1,11 @@
-use std::{collections::HashSet, io, path::PathBuf, rc::Rc};
+use std::{
+    collections::HashSet,
+    io,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use log::trace;
 
 use crate::walk::{
     directory::{Directory, EntryType},
@@ -7,14 +14,31 @@ use crate::walk::{
 
 #[derive(Debug)]
 pub struct DeepProtoWalker {
+    root: PathBuf,
     children: Vec<Directory>,
-    exclude: Rc<HashSet<PathBuf>>,
+    exclude: Arc<HashSet<PathBuf>>,
+    recursive: bool,
 }
 
 impl DeepProtoWalker {
-    pub fn new<P: Into<PathBuf>>(path: P, exclude: Rc<HashSet<PathBuf>>) -> Self {
-        let children = vec![Directory::new(path)];
-        Self { children, exclude }
+    pub fn new<P: Into<PathBuf>>(path: P, exclude: Arc<HashSet<PathBuf>>) -> Self {
+        let root = path.into();
+        let children = vec![Directory::new(&root)];
+        Self {
+            root,
+            children,
+            exclude,
+            recursive: true,
+        }
+    }
+
+    /// Sets whether protos from subdirectories should be returned too.
+    pub fn set_recursive(&mut self, recursive: bool) {
+        self.recursive = recursive;
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
     }
 
     fn should_skip(&self, path: &PathBuf) -> bool {
@@ -34,12 +58,13 @@ impl Iterator for DeepProtoWalker {
             match package.next() {
                 Some(Ok(entry)) => match entry {
                     EntryType::Dir(path) => {
-                        if !self.should_skip(&path) {
+                        if self.recursive && !self.should_skip(&path) {
                             push_package = Some(path)
                         }
                     }
                     EntryType::Proto(path) => {
                         if !self.should_skip(&path) {
+                            trace!("found {:?}", path);
                             return Some(Ok(path));
                         }
                     }
@@ -35,7 +35,7 @@ impl Directory {
         }
 
         let filename = entry.file_name();
-        match filename.to_str().map_or(false, |n| n.ends_with(".proto")) {
+        match filename.to_str().is_some_and(|n| n.ends_with(".proto")) {
             true => Ok(EntryType::Proto(path)),
             false => Ok(EntryType::Unknown(path)),
         }
@@ -46,7 +46,7 @@ impl Iterator for Directory {
     type Item = io::Result<EntryType>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        if let None = self.content {
+        if self.content.is_none() {
             match fs::read_dir(&self.path) {
                 Err(e) => return Some(Err(e)),
                 Ok(c) => self.content = Some(c),
@@ -0,0 +1,144 @@
+use std::{
+    collections::{BTreeSet, HashMap, HashSet},
+    fs, io,
+    path::{Path, PathBuf},
+    sync::Arc,
+    thread,
+    time::{Duration, SystemTime},
+};
+
+use log::{debug, trace};
+
+use crate::walk::deep::DeepProtoWalker;
+
+/// Interval between scans of watched files.
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Time files have to stay unchanged before their changes are reported, so an
+/// editor saving or a checkout touching many files causes a single run.
+const DEBOUNCE: Duration = Duration::from_millis(300);
+
+/// Modification time and size of every watched file.
+type Snapshot = HashMap<PathBuf, (Option<SystemTime>, u64)>;
+
+/// Watches protos under the root directory and a few extra files for changes.
+///
+/// Files are polled which works the same on every platform and file system.
+#[derive(Debug)]
+pub struct Watcher {
+    root: PathBuf,
+    exclude: Arc<HashSet<PathBuf>>,
+    files: Vec<PathBuf>,
+    snapshot: Option<Snapshot>,
+}
+
+impl Watcher {
+    pub fn new<P: Into<PathBuf>>(root: P) -> Self {
+        Self {
+            root: root.into(),
+            exclude: Arc::new(HashSet::new()),
+            files: vec![],
+            snapshot: None,
+        }
+    }
+
+    /// Sets paths relative to the root which aren't watched.
+    pub fn set_exclude<I, P>(&mut self, iter: I)
+    where
+        I: Iterator<Item = P>,
+        P: AsRef<Path>,
+    {
+        let exclude = iter.map(|p| self.root.join(p)).collect();
+        self.exclude = Arc::new(exclude);
+    }
+
+    /// Watches a file which isn't a proto under the root, e.g. the config.
+    pub fn add_file<P: Into<PathBuf>>(&mut self, path: P) {
+        self.files.push(path.into());
+    }
+
+    /// Blocks until watched files change and returns paths of changed, added
+    /// and removed files.
+    ///
+    /// Only changes made after the first call are reported. If files can't be
+    /// scanned, the next call reports changes since the last successful scan.
+    pub fn wait(&mut self) -> io::Result<Vec<PathBuf>> {
+        let mut previous = match self.snapshot.take() {
+            Some(snapshot) => snapshot,
+            None => self.scan().inspect_err(|_| {
+                // so that waiting again right away doesn't spin
+                thread::sleep(POLL_INTERVAL);
+            })?,
+        };
+
+        match self.poll(&mut previous) {
+            Ok((changed, current)) => {
+                self.snapshot = Some(current);
+                Ok(changed)
+            }
+            Err(e) => {
+                self.snapshot = Some(previous);
+                Err(e)
+            }
+        }
+    }
+
+    /// Polls files until they differ from `previous`, returns changed files
+    /// along with the new snapshot.
+    fn poll(&self, previous: &mut Snapshot) -> io::Result<(Vec<PathBuf>, Snapshot)> {
+        loop {
+            thread::sleep(POLL_INTERVAL);
+            let mut current = self.scan()?;
+            if current == *previous {
+                continue;
+            }
+
+            loop {
+                thread::sleep(DEBOUNCE);
+                let next = self.scan()?;
+                if next == current {
+                    break;
+                }
+
+                trace!("watched files are still changing");
+                current = next;
+            }
+
+            let changed = changes(previous, &current);
+            if changed.is_empty() {
+                *previous = current;
+                continue;
+            }
+
+            debug!("changed files: {:?}", changed);
+            return Ok((changed, current));
+        }
+    }
+
+    fn scan(&self) -> io::Result<Snapshot> {
+        let protos = DeepProtoWalker::new(&self.root, Arc::clone(&self.exclude));
+        let mut snapshot = HashMap::new();
+        for path in protos.chain(self.files.iter().cloned().map(Ok)) {
+            let path = path?;
+            let metadata = match fs::metadata(&path) {
+                Ok(metadata) => metadata,
+                // removed while scanning or a missing extra file
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e),
+            };
+
+            snapshot.insert(path, (metadata.modified().ok(), metadata.len()));
+        }
+
+        Ok(snapshot)
+    }
+}
+
+fn changes(previous: &Snapshot, current: &Snapshot) -> Vec<PathBuf> {
+    let paths: BTreeSet<_> = previous.keys().chain(current.keys()).collect();
+    paths
+        .into_iter()
+        .filter(|p| previous.get(*p) != current.get(*p))
+        .cloned()
+        .collect()
+}